@@ -175,7 +175,10 @@ pub struct EpubBuilder<Z: Zip> {
     stylesheet: bool,
     inline_toc: bool,
     escape_html: bool,
-    meta_opf: Vec<MetadataOpf>
+    meta_opf: Vec<MetadataOpf>,
+    resolution: (u32, u32),
+    /// primary-writing-mode, follows the direction once that is set
+    writing_mode: PageDirection,
 }
 
 impl<Z: Zip> EpubBuilder<Z> {
@@ -191,7 +194,9 @@ impl<Z: Zip> EpubBuilder<Z> {
             stylesheet: false,
             inline_toc: false,
             escape_html: true,
-            meta_opf: Vec::new()
+            meta_opf: Vec::new(),
+            resolution: (1072, 1448),
+            writing_mode: PageDirection::Rtl,
         };
 
         epub.zip
@@ -227,6 +232,14 @@ impl<Z: Zip> EpubBuilder<Z> {
     }
     
 
+    /// Set the screen size the pages were made for, in pixels (default: 1072x1448)
+    ///
+    /// Written to `content.opf` as `original-resolution`.
+    pub fn original_resolution(&mut self, width: u32, height: u32) -> &mut Self {
+        self.resolution = (width, height);
+        self
+    }
+
     /// Add custom <meta> to `content.opf`
     /// Syntax: `self.add_metadata_opf(name, content)`
     /// 
@@ -284,7 +297,10 @@ impl<Z: Zip> EpubBuilder<Z> {
             }
             "title" => self.metadata.title = value.into(),
             "lang" => self.metadata.lang = value.into(),
-            "direction" => self.metadata.direction = PageDirection::from_str(&value.into())?,
+            "direction" => {
+                self.metadata.direction = PageDirection::from_str(&value.into())?;
+                self.writing_mode = self.metadata.direction;
+            }
             "generator" => self.metadata.generator = value.into(),
             "description" => {
                 let value = value.into();
@@ -624,13 +640,18 @@ impl<Z: Zip> EpubBuilder<Z> {
             "<meta name=\"fixed-layout\" content=\"true\"/>"
         ));
         optional.push(format!(
-            "<meta name=\"original-resolution\" content=\"1072x1448\"/>"
+            "<meta name=\"original-resolution\" content=\"{}x{}\"/>",
+            self.resolution.0, self.resolution.1
         ));
         optional.push(format!(
             "<meta name=\"book-type\" content=\"comic\"/>"
         ));
         optional.push(format!(
-            "<meta name=\"primary-writing-mode\" content=\"horizontal-rl\"/>"
+            "<meta name=\"primary-writing-mode\" content=\"horizontal-{}\"/>",
+            match self.writing_mode {
+                PageDirection::Rtl => "rl",
+                PageDirection::Ltr => "lr",
+            }
         ));
         optional.push(format!(
             "<meta name=\"zero-gutter\" content=\"true\"/>"
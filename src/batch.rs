@@ -0,0 +1,221 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::KiyomiConfig,
+    email::{self, SharedMailer},
+    limits::Limiter,
+    pipeline, retry, template,
+};
+
+/// Called once the epub was sent, or given up on
+pub type Done = Box<dyn FnOnce(Result<(), retry::Failure>) + Send>;
+
+struct Item {
+    epub: PathBuf,
+    series: String,
+    subject: String,
+    body: String,
+    bytes: u64,
+    /// what it adds to the size of the email
+    encoded: u64,
+    kiyomi_config: Arc<KiyomiConfig>,
+    done: Done,
+}
+
+/// Collects finished epubs and sends them together (`[batch]`). The first epub opens a window of
+/// `batch.window` seconds, everything that arrives meanwhile goes into the same email for as
+/// long as it stays under `options.size_limit` and `batch.max_attachments`.
+pub struct Batcher {
+    tx: mpsc::Sender<Item>,
+    thread: JoinHandle<()>,
+}
+
+impl Batcher {
+    pub fn start(mailer: Arc<SharedMailer>, limiter: Arc<Limiter>) -> std::io::Result<Batcher> {
+        let (tx, rx) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("kiyomi-batch".to_string())
+            .spawn(move || run(rx, &mailer, &limiter))?;
+        Ok(Batcher { tx, thread })
+    }
+
+    /// Queues an epub. `done` is called on the batcher's thread
+    pub fn submit(&self, epub: &Path, series: &str, subject: &str, body: &str, kiyomi_config: Arc<KiyomiConfig>, done: Done) {
+        let bytes = match std::fs::metadata(epub) {
+            Ok(m) => m.len(),
+            Err(e) => {
+                done(Err(retry::Failure {
+                    error: format!("{:?}: {}", epub, e).into(),
+                    attempts: 0,
+                }));
+                return;
+            }
+        };
+
+        let item = Item {
+            epub: epub.to_path_buf(),
+            series: series.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            bytes,
+            encoded: email::encoded_size(bytes),
+            kiyomi_config,
+            done,
+        };
+        // the thread only stops once we're dropped
+        let _ = self.tx.send(item);
+    }
+
+    /// Sends what is still waiting and stops
+    pub fn finish(self) {
+        drop(self.tx);
+        let _ = self.thread.join();
+    }
+}
+
+fn run(rx: mpsc::Receiver<Item>, mailer: &SharedMailer, limiter: &Limiter) {
+    let mut pending: Vec<Item> = Vec::new();
+    let mut opened = Instant::now();
+
+    loop {
+        let received = match pending.first() {
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(first) => {
+                let window = Duration::from_secs(first.kiyomi_config.batch.window);
+                rx.recv_timeout(window.saturating_sub(opened.elapsed()))
+            }
+        };
+
+        match received {
+            Ok(item) => {
+                let limit = item.kiyomi_config.options.size_limit * 1024 * 1024;
+                let max = item.kiyomi_config.batch.max_attachments;
+                let sizes: Vec<u64> = pending.iter().map(|i| i.encoded).collect();
+                if !pending.is_empty() && !fits(&sizes, item.encoded, limit, max) {
+                    flush(&mut pending, mailer, limiter);
+                }
+
+                if pending.is_empty() {
+                    opened = Instant::now();
+                }
+                pending.push(item);
+                if pending.len() >= max {
+                    flush(&mut pending, mailer, limiter);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => flush(&mut pending, mailer, limiter),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                flush(&mut pending, mailer, limiter);
+                break;
+            }
+        }
+    }
+}
+
+/// Whether an attachment of `next` bytes still fits into an email that has `sizes` already.
+/// An attachment too large for any email still goes out, on its own
+fn fits(sizes: &[u64], next: u64, limit: u64, max_attachments: usize) -> bool {
+    sizes.is_empty() || (sizes.len() < max_attachments && sizes.iter().sum::<u64>() + next <= limit)
+}
+
+/// The placeholders of `templates.batch_subject` for an email with all of `items`
+fn values(items: &[Item], kiyomi_config: &KiyomiConfig) -> Vec<(&'static str, String)> {
+    let mut series: Vec<&str> = Vec::new();
+    for item in items {
+        if !series.contains(&item.series.as_str()) {
+            series.push(&item.series);
+        }
+    }
+    let bytes: u64 = items.iter().map(|i| i.bytes).sum();
+    vec![
+        ("count", items.len().to_string()),
+        ("series", series.join(", ")),
+        ("size", format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))),
+        ("subject", kiyomi_config.smtp.subject.clone()),
+    ]
+}
+
+fn flush(pending: &mut Vec<Item>, mailer: &SharedMailer, limiter: &Limiter) {
+    let items = std::mem::take(pending);
+    let Some(last) = items.last() else { return };
+    // the newest settings win
+    let kiyomi_config = last.kiyomi_config.clone();
+
+    let subject = match items.as_slice() {
+        [only] => only.subject.clone(),
+        _ => template::render(&kiyomi_config.templates.batch_subject, &values(&items, &kiyomi_config)),
+    };
+    // one text for all of them, unless they say different things
+    let mut bodies: Vec<&str> = items.iter().map(|i| i.body.as_str()).collect();
+    bodies.dedup();
+    let body = bodies.join("\n\n");
+    let epubs: Vec<&Path> = items.iter().map(|i| i.epub.as_path()).collect();
+
+    let result = retry::retry(&kiyomi_config.retry, || {
+        pipeline::send_files(&epubs, &subject, &body, &kiyomi_config, mailer, limiter)
+    });
+
+    match &result {
+        Ok(_) => log::info!("sent {} epub(s) in one email", items.len()),
+        Err(f) => log::error!("couldn't send {} epub(s) in one email: {}", items.len(), f.error),
+    }
+
+    for item in items {
+        let outcome = match &result {
+            Ok(_) => Ok(()),
+            Err(f) => Err(retry::Failure {
+                error: f.error.to_string().into(),
+                attempts: f.attempts,
+            }),
+        };
+        (item.done)(outcome);
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn batches_stay_under_the_limits() {
+    let mb = 1024 * 1024;
+    let limit = 25 * mb;
+
+    // 10 MB of epub is about 13.5 MB of email
+    assert!(email::encoded_size(10 * mb) > 13 * mb);
+    let ten = email::encoded_size(10 * mb);
+
+    assert!(fits(&[], ten, limit, 25));
+    assert!(!fits(&[ten], ten, limit, 25));
+    assert!(fits(&[ten], email::encoded_size(5 * mb), limit, 25));
+    assert!(!fits(&[1, 1], 1, limit, 2));
+
+    // too large for any email, it goes alone
+    assert!(fits(&[], email::encoded_size(30 * mb), limit, 25));
+}
+
+#[test]
+fn batched_emails_have_a_subject_of_their_own() {
+    let mut kiyomi_config = KiyomiConfig::default();
+    kiyomi_config.smtp.subject = "manga".into();
+    kiyomi_config.templates.batch_subject = "{count} from {series} ({size}) {subject}".into();
+    let item = |series: &str| Item {
+        epub: PathBuf::from(format!("{}.epub", series)),
+        series: series.into(),
+        subject: String::new(),
+        body: String::new(),
+        bytes: 1024 * 1024,
+        encoded: 0,
+        kiyomi_config: Arc::new(KiyomiConfig::default()),
+        done: Box::new(|_| {}),
+    };
+
+    let items = [item("Frieren"), item("Berserk"), item("Frieren")];
+    let subject = template::render(&kiyomi_config.templates.batch_subject, &values(&items, &kiyomi_config));
+    assert_eq!(subject, "3 from Frieren, Berserk (3.0 MB) manga");
+}
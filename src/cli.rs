@@ -0,0 +1,128 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::config::{ConfigSource, Override};
+
+/// kiyomi - .cbz file watcher for kindle
+///
+/// Without a command, kiyomi watches the manga directory (same as `kiyomi watch`).
+///
+/// Config values are taken from, lowest to highest precedence: built-in defaults, the config
+/// file, KIYOMI_<SECTION>__<KEY> environment variables (e.g. KIYOMI_SMTP__PASSWORD) and the flags
+/// below.
+#[derive(Debug, Parser)]
+#[command(name = "kiyomi", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Config file to use instead of ~/.config/kiyomi.toml
+    #[arg(long, short, value_name = "PATH", env = "KIYOMI_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Set any config value, e.g. `--set smtp.port=587`. May be given several times
+    #[arg(long = "set", short = 's', value_name = "KEY=VALUE", value_parser = Override::parse, global = true)]
+    pub set: Vec<Override>,
+
+    /// Directory to watch (directories.manga)
+    #[arg(long, value_name = "DIR", global = true)]
+    pub manga_dir: Option<PathBuf>,
+
+    /// Where to send the manga (smtp.to_email)
+    #[arg(long, value_name = "EMAIL", global = true)]
+    pub to_email: Option<String>,
+
+    /// Number of chapters handled at the same time (options.workers)
+    #[arg(long, value_name = "N", global = true)]
+    pub workers: Option<usize>,
+
+    /// Delete .cbz files once they are sent (options.delete)
+    #[arg(long, global = true)]
+    pub delete: bool,
+
+    /// Build the epubs but don't send or delete anything, only show what would happen
+    /// (options.dry_run)
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Where a dry run puts the epubs (options.dry_run_dir). Implies --dry-run
+    #[arg(long, value_name = "DIR", global = true)]
+    pub dry_run_dir: Option<PathBuf>,
+
+    /// error, warn, info, debug or trace (log.level)
+    #[arg(long, value_name = "LEVEL", global = true)]
+    pub log_level: Option<String>,
+
+    /// text or json (log.format)
+    #[arg(long, value_name = "FORMAT", global = true)]
+    pub log_format: Option<String>,
+
+    /// Also write the log to this file (log.file)
+    #[arg(long, value_name = "PATH", global = true)]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Watch the manga directory and send new chapters to the kindle
+    Watch,
+    /// Convert a .cbz to epub(s) without sending anything
+    Convert {
+        cbz: PathBuf,
+        /// Where to put the epubs
+        #[arg(long, short, value_name = "DIR", default_value = ".")]
+        output: PathBuf,
+    },
+    /// Email epub files to the kindle
+    Send {
+        #[arg(required = true)]
+        epubs: Vec<PathBuf>,
+    },
+    /// Convert and send a .cbz again, whether or not it was sent before. Without a file, try
+    /// everything in the dead-letter directory again
+    Resend { cbz: Option<PathBuf> },
+    /// Show what kiyomi has processed
+    Status {
+        /// List sent files too, not only those still pending or failed
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+impl Cli {
+    pub fn config_source(&self) -> ConfigSource {
+        let mut overrides = Vec::new();
+
+        if let Some(dir) = &self.manga_dir {
+            overrides.push(Override::new("directories.manga", &dir.to_string_lossy(), "--manga-dir"));
+        }
+        if let Some(email) = &self.to_email {
+            overrides.push(Override::new("smtp.to_email", email, "--to-email"));
+        }
+        if let Some(workers) = self.workers {
+            overrides.push(Override::new("options.workers", &workers.to_string(), "--workers"));
+        }
+        if self.delete {
+            overrides.push(Override::new("options.delete", "true", "--delete"));
+        }
+        if self.dry_run || self.dry_run_dir.is_some() {
+            overrides.push(Override::new("options.dry_run", "true", "--dry-run"));
+        }
+        if let Some(dir) = &self.dry_run_dir {
+            overrides.push(Override::new("options.dry_run_dir", &dir.to_string_lossy(), "--dry-run-dir"));
+        }
+        if let Some(level) = &self.log_level {
+            overrides.push(Override::new("log.level", level, "--log-level"));
+        }
+        if let Some(format) = &self.log_format {
+            overrides.push(Override::new("log.format", format, "--log-format"));
+        }
+        if let Some(file) = &self.log_file {
+            overrides.push(Override::new("log.file", &file.to_string_lossy(), "--log-file"));
+        }
+        // --set comes last so it wins over everything
+        overrides.extend(self.set.iter().cloned());
+
+        ConfigSource::new(self.config.clone(), overrides)
+    }
+}
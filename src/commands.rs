@@ -0,0 +1,207 @@
+//! The one-shot subcommands. `kiyomi watch` lives in `daemon`.
+
+use std::{
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    config::{self, ConfigSource, KiyomiConfig},
+    daemon, deadletter,
+    email::SharedMailer,
+    jobs::{JobState, JobStore},
+    limits::Limiter,
+    logging, pipeline, retry, workdir,
+};
+
+fn load(config_source: &ConfigSource) -> Result<KiyomiConfig, Box<dyn Error>> {
+    let kiyomi_config = config::get_config(config_source).map_err(|e| format!("config error: {}", e))?;
+    logging::configure(&kiyomi_config.log).map_err(|e| format!("config error: {}", e))?;
+    Ok(kiyomi_config)
+}
+
+/// `kiyomi convert <cbz> -o <dir>`: build the epubs, send nothing
+pub fn convert(config_source: &ConfigSource, cbz: &Path, output: &Path) -> Result<(), Box<dyn Error>> {
+    let kiyomi_config = load(config_source)?;
+    config::validate_templates(&kiyomi_config.templates)
+        .and_then(|_| config::validate_image(&kiyomi_config.image))
+        .map_err(|e| format!("config error: {}", e))?;
+
+    std::fs::create_dir_all(output)?;
+    let _scope = logging::job_scope(cbz);
+    for part in pipeline::convert(cbz, &kiyomi_config, output)? {
+        println!("{}", part.path.display());
+    }
+
+    Ok(())
+}
+
+/// `kiyomi send <epub>...`: email existing epubs as they are
+pub fn send(config_source: &ConfigSource, epubs: &[std::path::PathBuf]) -> Result<(), Box<dyn Error>> {
+    let kiyomi_config = load(config_source)?;
+    config::validate_smtp_config(&kiyomi_config).map_err(|e| format!("config error: {}", e))?;
+
+    // one connection for all of them
+    let mailer = SharedMailer::default();
+    let limiter = Limiter::default();
+    let mut failed = 0;
+    for epub in epubs {
+        let subject = pipeline::file_subject(epub, &kiyomi_config);
+        let body = pipeline::file_body(epub, &kiyomi_config);
+        let sent = retry::retry(&kiyomi_config.retry, || {
+            pipeline::send_file(epub, &subject, &body, &kiyomi_config, &mailer, &limiter)
+        });
+        match sent {
+            Ok(_) => log::info!("sent {:?}", epub),
+            Err(f) => {
+                log::error!("couldn't send {:?}: {}", epub, f.error);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} file(s) couldn't be sent", failed, epubs.len()).into());
+    }
+    Ok(())
+}
+
+/// `kiyomi resend <cbz>`: run a file through the whole pipeline again, whatever its state
+pub fn resend(config_source: &ConfigSource, cbz: &Path) -> Result<(), Box<dyn Error>> {
+    let kiyomi_config = load(config_source)?;
+    config::validate_config(&kiyomi_config).map_err(|e| format!("config error: {}", e))?;
+
+    if !cbz.is_file() {
+        return Err(format!("{:?} is not a file", cbz).into());
+    }
+    // the store knows files by the absolute path the watcher reported
+    let cbz = std::path::absolute(cbz)?;
+
+    let mut job_store = JobStore::open().map_err(|e| format!("job store error: {}", e))?;
+    if kiyomi_config.options.dry_run {
+        job_store = job_store.into_scratch();
+    }
+    let job_store = Arc::new(Mutex::new(job_store));
+    job_store.lock().unwrap().set_state(&cbz, JobState::Queued)?;
+
+    let outgoing = daemon::Outgoing {
+        mailer: Arc::new(SharedMailer::default()),
+        limiter: Arc::new(Limiter::default()),
+        batcher: None,
+    };
+    daemon::process_job(&cbz, &job_store, &Arc::new(kiyomi_config), &outgoing);
+
+    let state = job_store.lock().unwrap().state(&cbz);
+    match state {
+        Some(JobState::Sent) => Ok(()),
+        _ => Err(format!("{:?} couldn't be sent", cbz).into()),
+    }
+}
+
+/// `kiyomi resend`: try everything in the dead-letter directory again. Entries that go through
+/// are removed and their .cbz recorded as sent, the others stay with an updated report.
+pub fn resend_dead_letters(config_source: &ConfigSource) -> Result<(), Box<dyn Error>> {
+    let kiyomi_config = load(config_source)?;
+    config::validate_smtp_config(&kiyomi_config).map_err(|e| format!("config error: {}", e))?;
+    let dry_run = kiyomi_config.options.dry_run;
+
+    let root = workdir::dead_letter_root(&kiyomi_config.directories)?;
+    let entries = deadletter::entries(&root)?;
+    if entries.is_empty() {
+        log::info!("nothing to resend in {:?}", root);
+        return Ok(());
+    }
+
+    let mut job_store = JobStore::open().map_err(|e| format!("job store error: {}", e))?;
+    if dry_run {
+        job_store = job_store.into_scratch();
+    }
+
+    let mailer = SharedMailer::default();
+    let limiter = Limiter::default();
+    let mut failed = 0;
+    for mut entry in entries {
+        let _scope = logging::job_scope(&entry.report.source);
+
+        let mut sent_files = Vec::new();
+        let mut remaining = Vec::new();
+        for part in std::mem::take(&mut entry.report.parts) {
+            let epub = entry.dir.join(&part.file);
+            let sent = retry::retry(&kiyomi_config.retry, || {
+                pipeline::send_file(&epub, &part.subject, &part.body, &kiyomi_config, &mailer, &limiter)
+            });
+            match sent {
+                Ok(_) => {
+                    log::info!("sent {:?}", epub);
+                    sent_files.push(epub);
+                }
+                Err(f) => {
+                    log::error!("couldn't send {:?}: {}", epub, f.error);
+                    remaining.push(deadletter::FailedPart {
+                        attempts: part.attempts + f.attempts,
+                        error: f.error.to_string(),
+                        ..part
+                    });
+                }
+            }
+        }
+
+        if dry_run {
+            continue;
+        }
+        if remaining.is_empty() {
+            let source = entry.report.source.clone();
+            entry.remove()?;
+            if let Err(e) = job_store.set_state(&source, JobState::Sent) {
+                log::error!("job store error: {}", e);
+            }
+            daemon::delete_sent(&source, &kiyomi_config);
+        } else {
+            failed += 1;
+            // the parts that went out this time are done with
+            for epub in sent_files {
+                let _ = std::fs::remove_file(epub);
+            }
+            entry.report.parts = remaining;
+            entry.touch();
+            entry.save()?;
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} chapter(s) are still in {:?}", failed, root).into());
+    }
+    Ok(())
+}
+
+/// `kiyomi status`: what the job store knows
+pub fn status(all: bool) -> Result<(), Box<dyn Error>> {
+    let job_store = JobStore::open().map_err(|e| format!("job store error: {}", e))?;
+    let jobs = job_store.jobs();
+
+    println!("job store: {:?}", job_store.path());
+    for state in [
+        JobState::Queued,
+        JobState::Converting,
+        JobState::Sending,
+        JobState::Sent,
+        JobState::Failed,
+    ] {
+        let count = jobs.iter().filter(|(_, s)| *s == state).count();
+        println!("{:>11}: {}", state.to_string(), count);
+    }
+
+    let listed: Vec<_> = jobs
+        .iter()
+        .filter(|(_, state)| all || *state != JobState::Sent)
+        .collect();
+    if !listed.is_empty() {
+        println!();
+    }
+    for (path, state) in listed {
+        println!("{:<10} {}", state.to_string(), path.display());
+    }
+
+    Ok(())
+}
@@ -1,110 +1,899 @@
 extern crate dirs;
 
-// Get the config file. If it doesn't exist, create a default one
-pub fn get_config() -> Result<toml::Value, Box<dyn std::error::Error>> {
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
-    let config_dir = match dirs::config_dir() {
-        Some(dir) => dir,
-        None => {
-            return Err("! the config directory could not be created".into());
+/// Everything in kiyomi.toml. Loaded once at startup and shared by every job.
+///
+/// Values come from, in increasing order of precedence: built-in defaults, the config file,
+/// `KIYOMI_*` environment variables and command line flags. Required values default to empty
+/// and are checked by `validate_config` once all layers are merged, so e.g. the password may
+/// come from the environment alone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KiyomiConfig {
+    pub smtp: SmtpConfig,
+    pub directories: DirectoriesConfig,
+    pub options: OptionsConfig,
+    pub watcher: WatcherConfig,
+    pub retry: RetryConfig,
+    pub limits: LimitsConfig,
+    pub batch: BatchConfig,
+    pub templates: TemplatesConfig,
+    pub image: ImageConfig,
+    /// `[series."<name>"]`, settings for one series only
+    pub series: BTreeMap<String, SeriesConfig>,
+    pub log: LogConfig,
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually port 465
+    #[default]
+    Tls,
+    /// plain connection upgraded with STARTTLS, usually port 587
+    Starttls,
+    /// no encryption at all, for relays on the local network
+    None,
+}
+
+impl SmtpSecurity {
+    pub fn default_port(self) -> u16 {
+        match self {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::None => 25,
         }
-    };
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub server: String,
+    /// defaults to 465, 587 or 25 depending on `security`
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    /// PEM file with an extra CA certificate to trust, e.g. for a self-signed server
+    pub ca_file: Option<PathBuf>,
+    /// don't check the server's certificate at all
+    pub accept_invalid_certs: bool,
+    /// seconds to wait for the server to connect and answer, 60 if not set
+    pub timeout: Option<u64>,
+    /// leave empty, along with the password, for servers that don't need a login
+    pub username: String,
+    /// the password itself. Use one of the `password_*` keys below to keep it out of the file
+    pub password: String,
+    /// read the password from this file, e.g. a systemd credential
+    pub password_file: Option<PathBuf>,
+    /// read the password from this environment variable
+    pub password_env: Option<String>,
+    /// run this through `sh -c` and use what it prints, e.g. `pass show kindle`
+    pub password_command: Option<String>,
+    pub from_email: String,
+    pub to_email: String,
+    pub subject: String,
+    /// how we log in: with the password, or with an OAuth2 access token
+    pub auth: SmtpAuth,
+    /// used when `auth = "xoauth2"`
+    pub oauth2: OAuth2Config,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpAuth {
+    #[default]
+    Password,
+    /// Gmail and Microsoft 365 without app passwords
+    Xoauth2,
+}
+
+/// Where access tokens for XOAUTH2 come from. Kiyomi trades the refresh token for short-lived
+/// access tokens and keeps the newest refresh token in `token_file`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OAuth2Config {
+    /// e.g. `https://oauth2.googleapis.com/token`
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// only needed by some providers, e.g. `https://outlook.office.com/SMTP.Send offline_access`
+    pub scope: Option<String>,
+    /// the first refresh token. Once `token_file` exists it is used instead
+    pub refresh_token: String,
+    /// where the current refresh token is kept, `~/.local/share/kiyomi/oauth2-refresh-token`
+    /// if not set
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DirectoriesConfig {
+    pub manga: PathBuf,
+    /// where epubs are built, `~/.cache/kiyomi/work` if not set
+    pub work: Option<PathBuf>,
+    /// where epubs that couldn't be sent end up, `~/.cache/kiyomi/dead-letter` if not set
+    pub dead_letter: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OptionsConfig {
+    /// delete the .cbz once it was sent
+    pub delete: bool,
+    /// in MB, manga larger than this is split into several emails
+    pub size_limit: u64,
+    /// send what was downloaded while kiyomi wasn't running
+    pub backfill: bool,
+    /// only backfill files modified in the last N days
+    pub backfill_days: Option<u64>,
+    pub workers: usize,
+    /// build everything but don't send or delete anything
+    pub dry_run: bool,
+    /// where a dry run leaves the epubs, `<work dir>/dry-run` if not set
+    pub dry_run_dir: Option<PathBuf>,
+}
+
+impl Default for OptionsConfig {
+    fn default() -> OptionsConfig {
+        OptionsConfig {
+            delete: false,
+            size_limit: 25,
+            backfill: false,
+            backfill_days: None,
+            workers: 1,
+            dry_run: false,
+            dry_run_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatcherMode {
+    Native,
+    Poll,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatcherConfig {
+    pub mode: WatcherMode,
+    /// seconds between scans in poll mode
+    pub poll_interval: u64,
+    /// how long a new file must stay untouched before it counts as written
+    pub debounce_ms: u64,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> WatcherConfig {
+        WatcherConfig {
+            mode: WatcherMode::Native,
+            poll_interval: 1,
+            debounce_ms: 2000,
+        }
+    }
+}
+
+/// How often a failed email is tried again before it goes to the dead-letter directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// retries after the first attempt, 0 to give up right away
+    pub retries: u32,
+    /// seconds before the first retry, doubled for every one after it
+    pub initial_delay: u64,
+    /// seconds, the longest we wait between two attempts
+    pub max_delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            retries: 3,
+            initial_delay: 30,
+            max_delay: 600,
+        }
+    }
+}
+
+/// How fast kiyomi may send, so a large batch of downloads doesn't get the account throttled.
+/// Emails over a limit wait until there's room again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// emails in any minute
+    pub per_minute: Option<u32>,
+    /// emails in any hour
+    pub per_hour: Option<u32>,
+    /// MB of epubs in any 24 hours
+    pub mb_per_day: Option<u64>,
+    /// local times nothing is sent at, e.g. `["22:00-07:00"]`. What comes in meanwhile waits
+    pub quiet_hours: Vec<String>,
+}
+
+/// Several epubs in one email instead of an email each, while they fit under
+/// `options.size_limit`. Only `kiyomi watch` batches
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub enabled: bool,
+    /// seconds to wait for more epubs once the first one is ready
+    pub window: u64,
+    /// most attachments in one email. Send to Kindle takes up to 25
+    pub max_attachments: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            enabled: false,
+            window: 60,
+            max_attachments: 25,
+        }
+    }
+}
+
+/// What the emails and epubs are called. `{series}`, `{title}`, `{chapter}`, `{part}`, `{parts}`,
+/// `{pages}`, `{size}` and `{subject}` (`smtp.subject`) are filled in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemplatesConfig {
+    pub subject: String,
+    pub body: String,
+    /// for an email with several epubs (`[batch]`): `{count}` of them, the `{series}` they're
+    /// from, their `{size}` and `{subject}`
+    pub batch_subject: String,
+    /// without the .epub. The book's title if not set
+    pub filename: Option<String>,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> TemplatesConfig {
+        TemplatesConfig {
+            subject: "{part}-{parts} {subject}".to_string(),
+            body: "Here's your manga!".to_string(),
+            batch_subject: "{subject}".to_string(),
+            filename: None,
+        }
+    }
+}
+
+/// Settings that differ for one series. The name is the directory its chapters are downloaded
+/// to, or the series in their ComicInfo.xml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeriesConfig {
+    pub crop: Option<bool>,
+    pub crop_tolerance: Option<u8>,
+    pub crop_max: Option<u8>,
+    pub spreads: Option<Spreads>,
+    pub direction: Option<Direction>,
+}
+
+impl KiyomiConfig {
+    /// `[image]` with what `[series."<name>"]` says for any of `names` applied
+    pub fn image_for(&self, names: &[&str]) -> ImageConfig {
+        let mut image = self.image.clone();
+        for series in names.iter().filter_map(|n| self.series.get(*n)) {
+            image.crop = series.crop.unwrap_or(image.crop);
+            image.crop_tolerance = series.crop_tolerance.unwrap_or(image.crop_tolerance);
+            image.crop_max = series.crop_max.unwrap_or(image.crop_max);
+            image.spreads = series.spreads.unwrap_or(image.spreads);
+            image.direction = series.direction.or(image.direction);
+        }
+        image
+    }
+}
+
+/// The screen of a kindle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Profile {
+    pub width: u32,
+    pub height: u32,
+    pub color: bool,
+}
+
+/// What happens to the pages before they go into the epub
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageConfig {
+    /// a kindle model or "WxH". Pages larger than its screen are scaled down to fit. Not set:
+    /// pages are used as they are
+    pub device: Option<String>,
+    /// 1-100, for pages that had to be re-encoded
+    pub jpeg_quality: u8,
+    /// 8-bit gray pages tuned by what follows, unless `device` has a color screen
+    pub grayscale: bool,
+    /// above 1 darkens the midtones, which e-ink tends to wash out
+    pub gamma: f32,
+    /// stretch each page's grays to go from black to white
+    pub autocontrast: bool,
+    /// round to the 16 grays an e-ink screen can show
+    pub quantize: bool,
+    /// cut off white or black borders around the art
+    pub crop: bool,
+    /// 0-255, how far a gray may be from the border's and still count as border
+    pub crop_tolerance: u8,
+    /// most that's cut off each side, in percent of the page
+    pub crop_max: u8,
+    /// what to do with pages wider than tall
+    pub spreads: Spreads,
+    /// the order split spreads are read in, and the direction pages are turned. Unset, spreads
+    /// are split right to left and the epub says nothing, so pages turn left to right
+    pub direction: Option<Direction>,
+}
+
+/// Double-page spreads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Spreads {
+    /// as they are, small on a portrait screen
+    #[default]
+    Keep,
+    /// turned 90° to fill the screen
+    Rotate,
+    /// cut into its two pages
+    Split,
+    /// the two pages, after the whole spread turned 90°
+    SplitRotate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// right to left, like most manga
+    Rtl,
+    Ltr,
+}
+
+impl Default for ImageConfig {
+    fn default() -> ImageConfig {
+        ImageConfig {
+            device: None,
+            jpeg_quality: 90,
+            grayscale: false,
+            gamma: 1.8,
+            autocontrast: true,
+            quantize: false,
+            crop: false,
+            crop_tolerance: 16,
+            crop_max: 10,
+            spreads: Spreads::Keep,
+            direction: None,
+        }
+    }
+}
+
+impl ImageConfig {
+    /// `image.device` looked up
+    pub fn profile(&self) -> Result<Option<Profile>, String> {
+        let Some(device) = &self.device else {
+            return Ok(None);
+        };
+        let (width, height, color) = match device.to_lowercase().as_str() {
+            "basic" => (1072, 1448, false),
+            "paperwhite" => (1236, 1648, false),
+            "oasis" => (1264, 1680, false),
+            "scribe" => (1860, 2480, false),
+            "colorsoft" => (1264, 1680, true),
+            custom => custom
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?, false)))
+                .filter(|(w, h, _)| *w > 0 && *h > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "image.device must be basic, paperwhite, oasis, scribe, colorsoft or a size like \"1264x1680\", got {:?}",
+                        device
+                    )
+                })?,
+        };
+        Ok(Some(Profile { width, height, color }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// error, warn, info, debug or trace
+    pub level: String,
+    pub format: LogFormat,
+    /// also append the log to this file
+    pub file: Option<PathBuf>,
+    /// in MB, the log file is rotated once it grows past this
+    pub max_size: u64,
+    /// how many rotated log files to keep
+    pub keep: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            file: None,
+            max_size: 10,
+            keep: 3,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn level_filter(&self) -> Result<log::LevelFilter, String> {
+        self.level
+            .parse()
+            .map_err(|_| format!("log.level must be one of error, warn, info, debug, trace, got {:?}", self.level))
+    }
+}
+
+/// A single value set from outside the config file
+#[derive(Debug, Clone)]
+pub struct Override {
+    /// `smtp.to_email`
+    key: String,
+    value: String,
+    /// where it came from, for error messages
+    origin: String,
+}
+
+impl Override {
+    /// `section.key=value`, as given to `--set`
+    pub fn parse(arg: &str) -> Result<Override, String> {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got {:?}", arg))?;
+        Ok(Override::new(key.trim(), value, "--set"))
+    }
+
+    pub fn new(key: &str, value: &str, origin: &str) -> Override {
+        Override {
+            key: key.to_string(),
+            value: value.to_string(),
+            origin: origin.to_string(),
+        }
+    }
+}
+
+/// Where the config comes from: the file and everything layered on top of it.
+/// Kept around so the config can be loaded again the same way.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    /// `--config`, `~/.config/kiyomi.toml` if not given
+    path: Option<PathBuf>,
+    overrides: Vec<Override>,
+}
+
+impl ConfigSource {
+    /// `cli` are the command line overrides. `KIYOMI_*` environment variables are read here and
+    /// go below them.
+    pub fn new(path: Option<PathBuf>, cli: Vec<Override>) -> ConfigSource {
+        let mut overrides = env_overrides();
+        overrides.extend(cli);
+        ConfigSource { path, overrides }
+    }
+
+    pub fn path(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => default_config_path(),
+        }
+    }
+}
+
+/// Every `KIYOMI_<SECTION>__<KEY>` variable, e.g. `KIYOMI_SMTP__FROM_EMAIL` for `smtp.from_email`
+fn env_overrides() -> Vec<Override> {
+    let mut overrides: Vec<Override> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let key = env_key(&name)?;
+            Some(Override::new(&key, &value, &name))
+        })
+        .collect();
+    // the environment has no order, make ours stable
+    overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+    overrides
+}
+
+/// `KIYOMI_SMTP__FROM_EMAIL` is `smtp.from_email`: a double underscore separates the tables, as
+/// keys have single ones. `None` for KIYOMI_CONFIG, the path of the file itself, and other such
+/// variables without a section
+fn env_key(name: &str) -> Option<String> {
+    let key = name.strip_prefix("KIYOMI_")?;
+    key.contains("__").then(|| key.to_lowercase().replace("__", "."))
+}
+
+/// `~/.config/kiyomi.toml`
+pub fn default_config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match dirs::config_dir() {
+        Some(dir) => Ok(dir.join("kiyomi.toml")),
+        None => Err("the config directory could not be found".into()),
+    }
+}
 
-    let config_path = config_dir.join("kiyomi.toml");
+// Get the config file and apply the overrides on top. If the default file doesn't exist, create it
+pub fn get_config(source: &ConfigSource) -> Result<KiyomiConfig, Box<dyn std::error::Error>> {
+    let config_path = source.path()?;
+
+    if source.path.is_some() && !config_path.exists() {
+        return Err(format!("config file {:?} does not exist", config_path).into());
+    }
 
     if !config_path.exists() {
         let default_config = r#"[smtp]
 server = "smtp.example.com"
 username = ""
 password = ""
+# or one of: password_file, password_env, password_command
 from_email = ""
 to_email = ""
 subject = "Manga"
 [directories]
 # manga = "/path/to/your/manga"
+# where epubs are built, must be outside the manga directory
+# work = "/path/to/work"
 [options]
 delete = false
+# send manga that was downloaded while kiyomi wasn't running
+backfill = false
+# backfill_days = 7
+[watcher]
+# "native" uses inotify, "poll" works on network filesystems
+mode = "native"
+# poll_interval = 1
         "#;
 
         match std::fs::write(&config_path, default_config) {
-            Ok(_) => println!("- created default config file at {:?}", config_path),
-            Err(e) => eprintln!("! couldn't create config file: {}", e),
+            Ok(_) => log::info!("created default config file at {:?}", config_path),
+            Err(e) => log::error!("couldn't create config file: {}", e),
         }
 
-        return Err(format!("! please edit the config file at {:?}", config_path).into());
+        return Err(format!("please edit the config file at {:?}", config_path).into());
     }
 
-    let config_str = std::fs::read_to_string(&config_path)?;
-    let config: toml::Value = toml::from_str(&config_str)?;
+    let config = load_config(&config_path)?;
+    let mut config = apply_overrides(config, &source.overrides)?;
+    config.smtp.resolve_password()?;
 
     Ok(config)
 }
 
-pub fn validate_config(config: &toml::Value) -> Result<(), Box<dyn std::error::Error>> {
-    let smtp = config.get("smtp").ok_or("missing [smtp] section")?;
-    let directories = config.get("directories").ok_or("missing [directories] section")?;
+impl SmtpConfig {
+    /// Fetches the password from wherever it is configured to come from and keeps it in
+    /// `password`. Only one source may be set.
+    fn resolve_password(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let sources = [
+            !self.password.is_empty(),
+            self.password_file.is_some(),
+            self.password_env.is_some(),
+            self.password_command.is_some(),
+        ];
+        if sources.iter().filter(|s| **s).count() > 1 {
+            return Err(
+                "only one of smtp.password, smtp.password_file, smtp.password_env and smtp.password_command may be set"
+                    .into(),
+            );
+        }
+
+        if let Some(file) = &self.password_file {
+            let secret = std::fs::read_to_string(file)
+                .map_err(|e| format!("smtp.password_file: couldn't read {:?}: {}", file, e))?;
+            self.password = secret.trim_end_matches(['\r', '\n']).to_string();
+        } else if let Some(var) = &self.password_env {
+            self.password = std::env::var(var)
+                .map_err(|e| format!("smtp.password_env: ${}: {}", var, e))?;
+        } else if let Some(command) = &self.password_command {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .map_err(|e| format!("smtp.password_command: couldn't run {:?}: {}", command, e))?;
+            if !output.status.success() {
+                return Err(format!("smtp.password_command: {:?} failed with {}", command, output.status).into());
+            }
+            let secret = String::from_utf8(output.stdout)
+                .map_err(|_| "smtp.password_command: the output isn't valid utf-8")?;
+            self.password = secret.trim_end_matches(['\r', '\n']).to_string();
+        }
+
+        Ok(())
+    }
+}
 
-    let server = smtp.get("server").ok_or("missing smtp.server")?;
-    let username = smtp.get("username").ok_or("missing smtp.username")?;
-    let password = smtp.get("password").ok_or("missing smtp.password")?;
-    let from_email = smtp.get("from_email").ok_or("missing smtp.from_email")?;
-    let to_email = smtp.get("to_email").ok_or("missing smtp.to_email")?;
-    let subject = smtp.get("subject").ok_or("missing smtp.subject")?;
+/// Reads and parses a config file. Syntax and type errors point at the line and column,
+/// keys we don't know about are reported and otherwise ignored.
+pub fn load_config(path: &Path) -> Result<KiyomiConfig, Box<dyn std::error::Error>> {
+    let config_str = std::fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read {:?}: {}", path, e))?;
 
-    let manga = directories.get("manga").ok_or("missing directories.manga")?;
+    let mut unknown = Vec::new();
+    let config: KiyomiConfig = serde_ignored::deserialize(
+        toml::Deserializer::new(&config_str),
+        |key| unknown.push(key.to_string()),
+    )
+    .map_err(|e| format!("{:?}: {}", path, e))?;
 
-    if server.as_str().is_none() {
-        return Err("smtp.server must be a string".into());
+    for key in unknown {
+        log::warn!("unknown config key {:?} in {:?}, ignoring it", key, path);
     }
 
-    if username.as_str().is_none() {
-        return Err("smtp.username must be a string".into());
+    Ok(config)
+}
+
+/// Layers the overrides over a loaded config. Each value is read as the type the key already has,
+/// so `KIYOMI_SMTP__PASSWORD=1234` stays a string while `KIYOMI_OPTIONS__WORKERS=4` is a number.
+fn apply_overrides(config: KiyomiConfig, overrides: &[Override]) -> Result<KiyomiConfig, Box<dyn std::error::Error>> {
+    if overrides.is_empty() {
+        return Ok(config);
     }
 
-    if password.as_str().is_none() {
-        return Err("smtp.password must be a string".into());
+    let mut tree = toml::Value::try_from(&config)?;
+
+    for o in overrides {
+        let path: Vec<String> = o.key.split('.').map(str::to_string).collect();
+        if path.len() < 2 || path.iter().any(|p| p.is_empty()) {
+            return Err(format!("{}: {:?} is not a config key", o.origin, path.join(".")).into());
+        }
+
+        let (leaf, tables) = path.split_last().unwrap();
+        let mut table = tree.as_table_mut().unwrap();
+        for name in tables {
+            table = table
+                .entry(name.as_str())
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| format!("{}: {} is not a table", o.origin, name))?;
+        }
+
+        let value = parse_override(table.get(leaf.as_str()), &o.value)
+            .map_err(|e| format!("{} ({}): {}", o.origin, path.join("."), e))?;
+        table.insert(leaf.clone(), value);
     }
 
-    if from_email.as_str().is_none() {
-        return Err("smtp.from_email must be a string".into());
+    let mut unknown = Vec::new();
+    let config: KiyomiConfig = serde_ignored::deserialize(tree, |key| unknown.push(key.to_string()))?;
+    for key in unknown {
+        log::warn!("unknown config key {:?} set from the command line or environment, ignoring it", key);
     }
 
-    if to_email.as_str().is_none() {
-        return Err("smtp.to_email must be a string".into());
+    Ok(config)
+}
+
+fn parse_override(existing: Option<&toml::Value>, raw: &str) -> Result<toml::Value, String> {
+    let literal = || {
+        toml::from_str::<toml::Table>(&format!("v = {}", raw))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+    };
+
+    match existing {
+        Some(toml::Value::String(_)) => Ok(toml::Value::String(raw.to_string())),
+        Some(toml::Value::Integer(_)) => raw
+            .trim()
+            .parse()
+            .map(toml::Value::Integer)
+            .map_err(|_| format!("expected a number, got {:?}", raw)),
+        Some(toml::Value::Float(_)) => raw
+            .trim()
+            .parse()
+            .map(toml::Value::Float)
+            .map_err(|_| format!("expected a number, got {:?}", raw)),
+        Some(toml::Value::Boolean(_)) => match raw.trim() {
+            "true" | "1" | "yes" => Ok(toml::Value::Boolean(true)),
+            "false" | "0" | "no" => Ok(toml::Value::Boolean(false)),
+            _ => Err(format!("expected true or false, got {:?}", raw)),
+        },
+        Some(_) => literal().ok_or_else(|| format!("couldn't parse {:?}", raw)),
+        // optional and unset: a TOML literal if it is one, a plain string otherwise
+        None => Ok(literal().unwrap_or_else(|| toml::Value::String(raw.to_string()))),
     }
+}
 
-    if subject.as_str().is_none() {
-        return Err("smtp.subject must be a string".into());
+/// Only what's needed to send mail, for commands that don't touch the manga directory
+pub fn validate_smtp_config(config: &KiyomiConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let smtp = &config.smtp;
+
+    // check length
+    for (key, value) in [
+        ("smtp.server", &smtp.server),
+        ("smtp.from_email", &smtp.from_email),
+        ("smtp.to_email", &smtp.to_email),
+        ("smtp.subject", &smtp.subject),
+    ] {
+        if value.is_empty() {
+            return Err(format!("{} must not be empty", key).into());
+        }
     }
 
-    if manga.as_str().is_none() {
-        return Err("directories.manga must be a string".into());
+    match smtp.auth {
+        // a relay may not want a login at all, but half of one is a mistake
+        SmtpAuth::Password => {
+            if smtp.username.is_empty() != smtp.password.is_empty() {
+                return Err("smtp.username and smtp.password (or the file, variable or command it comes from) must both be set, or both be empty".into());
+            }
+        }
+        SmtpAuth::Xoauth2 => {
+            for (key, value) in [
+                ("smtp.username", &smtp.username),
+                ("smtp.oauth2.token_url", &smtp.oauth2.token_url),
+                ("smtp.oauth2.client_id", &smtp.oauth2.client_id),
+            ] {
+                if value.is_empty() {
+                    return Err(format!("{} must be set for auth = \"xoauth2\"", key).into());
+                }
+            }
+        }
     }
 
-    if !std::path::Path::new(manga.as_str().unwrap()).exists() {
+    if let Some(ca_file) = &smtp.ca_file {
+        if !ca_file.is_file() {
+            return Err(format!("smtp.ca_file {:?} does not exist", ca_file).into());
+        }
+    }
+
+    validate_templates(&config.templates)
+}
+
+pub fn validate_templates(templates: &TemplatesConfig) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::template::{check, BATCH_PLACEHOLDERS, PLACEHOLDERS};
+
+    check(&templates.subject, PLACEHOLDERS).map_err(|e| format!("templates.subject: {}", e))?;
+    check(&templates.body, PLACEHOLDERS).map_err(|e| format!("templates.body: {}", e))?;
+    check(&templates.batch_subject, BATCH_PLACEHOLDERS).map_err(|e| format!("templates.batch_subject: {}", e))?;
+
+    if let Some(filename) = &templates.filename {
+        // the file is named before it's built, its size isn't known yet
+        let names: Vec<&str> = PLACEHOLDERS.iter().copied().filter(|p| *p != "size").collect();
+        check(filename, &names).map_err(|e| format!("templates.filename: {}", e))?;
+        if filename.trim().is_empty() {
+            return Err("templates.filename must not be empty".into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_image(image: &ImageConfig) -> Result<(), Box<dyn std::error::Error>> {
+    image.profile()?;
+    if !(1..=100).contains(&image.jpeg_quality) {
+        return Err("image.jpeg_quality must be between 1 and 100".into());
+    }
+    if !image.gamma.is_finite() || image.gamma <= 0.0 {
+        return Err("image.gamma must be greater than 0".into());
+    }
+    if image.crop_max > 45 {
+        return Err("image.crop_max must be 45 (percent) or less".into());
+    }
+    Ok(())
+}
+
+pub fn validate_config(config: &KiyomiConfig) -> Result<(), Box<dyn std::error::Error>> {
+    validate_smtp_config(config)?;
+
+    let manga = &config.directories.manga;
+    if manga.as_os_str().is_empty() {
+        return Err("directories.manga must be set".into());
+    }
+    if !manga.exists() {
         return Err("directories.manga path does not exist".into());
     }
 
-    // check length
-    if username.as_str().unwrap().len() == 0 {
-        return Err("smtp.username must not be empty".into());
+    if let Some(work) = &config.directories.work {
+        // anything we write in there would wake the watcher up again
+        let manga_dir = std::fs::canonicalize(manga)?;
+        let work_dir = std::fs::canonicalize(work).unwrap_or_else(|_| work.clone());
+        if work_dir.starts_with(&manga_dir) {
+            return Err("directories.work must not be inside directories.manga".into());
+        }
+    }
+
+    if config.options.size_limit < 1 {
+        return Err("options.size_limit must be greater than 0".into());
     }
 
-    if password.as_str().unwrap().len() == 0 {
-        return Err("smtp.password must not be empty".into());
+    if config.options.workers < 1 {
+        return Err("options.workers must be greater than 0".into());
     }
 
-    if from_email.as_str().unwrap().len() == 0 {
-        return Err("smtp.from_email must not be empty".into());
+    if config.watcher.poll_interval < 1 {
+        return Err("watcher.poll_interval must be greater than 0".into());
     }
 
-    if to_email.as_str().unwrap().len() == 0 {
-        return Err("smtp.to_email must not be empty".into());
+    for (key, value) in [
+        ("limits.per_minute", config.limits.per_minute.map(u64::from)),
+        ("limits.per_hour", config.limits.per_hour.map(u64::from)),
+        ("limits.mb_per_day", config.limits.mb_per_day),
+    ] {
+        if value == Some(0) {
+            return Err(format!("{} must be greater than 0", key).into());
+        }
+    }
+    if config.batch.max_attachments < 1 {
+        return Err("batch.max_attachments must be greater than 0".into());
     }
 
-    if subject.as_str().unwrap().len() == 0 {
-        return Err("smtp.subject must not be empty".into());
+    for window in &config.limits.quiet_hours {
+        crate::limits::parse_quiet_hours(window)?;
     }
 
+    validate_image(&config.image)?;
+    for (name, series) in &config.series {
+        if series.crop_max.is_some_and(|m| m > 45) {
+            return Err(format!("series.\"{}\".crop_max must be 45 (percent) or less", name).into());
+        }
+    }
+
+    config.log.level_filter()?;
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn overrides_keep_the_key_type() {
+    let env = |name: &str, value: &str| Override::new(&env_key(name).unwrap(), value, name);
+    let mut overrides = vec![
+        env("KIYOMI_SMTP__FROM_EMAIL", "me@example.com"),
+        env("KIYOMI_SMTP__PASSWORD", "1234"),
+        Override::new("options.workers", "3", "--workers"),
+        Override::new("smtp.port", "587", "--set"),
+    ];
+    // unset optional keys, and keys that start with the name of another key
+    overrides.extend([
+        env("KIYOMI_SMTP__PASSWORD_FILE", "/run/secrets/smtp"),
+        env("KIYOMI_SMTP__PASSWORD_ENV", "MAIL_PASSWORD"),
+        env("KIYOMI_SMTP__PASSWORD_COMMAND", "pass show mail"),
+        env("KIYOMI_OPTIONS__BACKFILL_DAYS", "7"),
+        env("KIYOMI_OPTIONS__DRY_RUN_DIR", "/tmp/kiyomi-dry-run"),
+    ]);
+    assert_eq!(env_key("KIYOMI_CONFIG"), None);
+    assert_eq!(env_key("KIYOMI_SMTP_PASSWORD"), None);
+
+    let config = apply_overrides(KiyomiConfig::default(), &overrides).unwrap();
+    assert_eq!(config.smtp.from_email, "me@example.com");
+    assert_eq!(config.smtp.password, "1234");
+    assert_eq!(config.options.workers, 3);
+    assert_eq!(config.smtp.port, Some(587));
+    assert_eq!(config.smtp.password_file, Some(PathBuf::from("/run/secrets/smtp")));
+    assert_eq!(config.smtp.password_env.as_deref(), Some("MAIL_PASSWORD"));
+    assert_eq!(config.smtp.password_command.as_deref(), Some("pass show mail"));
+    assert_eq!(config.options.backfill_days, Some(7));
+    assert_eq!(config.options.dry_run_dir, Some(PathBuf::from("/tmp/kiyomi-dry-run")));
+
+    let bad = vec![Override::new("options.workers", "many", "--workers")];
+    assert!(apply_overrides(KiyomiConfig::default(), &bad).is_err());
+}
+
+#[test]
+fn password_sources() {
+    let mut smtp = SmtpConfig {
+        password_command: Some("printf 'hunter2\\n'".into()),
+        ..Default::default()
+    };
+    smtp.resolve_password().unwrap();
+    assert_eq!(smtp.password, "hunter2");
+
+    // the password is now set as well, which counts as a second source
+    assert!(smtp.resolve_password().is_err());
+
+    let mut smtp = SmtpConfig {
+        password_command: Some("exit 1".into()),
+        ..Default::default()
+    };
+    assert!(smtp.resolve_password().is_err());
+}
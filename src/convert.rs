@@ -1,11 +1,19 @@
 use std::{
+    collections::HashSet,
     fs::File,
     io::{self, BufReader, Read},
     path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
 };
 use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ZipLibrary};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, GrayImage, ImageFormat};
 use zip::ZipArchive;
-use infer;
+
+use crate::config::{Direction, ImageConfig, Profile, Spreads};
 
 pub struct ImageFile {
     pub file_name: String, // original file name from the ZIP
@@ -19,16 +27,17 @@ pub struct ComicInfo {
     pub title: Option<String>,
     pub writer: Option<String>,
     pub series: Option<String>,
+    /// the chapter number
+    pub number: Option<String>,
 }
 
 pub fn extract_images_from_cbz<P: AsRef<Path>>(cbz_path: P) -> io::Result<(Vec<ImageFile>, Option<ComicInfo>)> {
-    println!("- extracting images from cbz");
+    log::debug!("extracting images from cbz");
     let file = File::open(cbz_path)?;
     let mut comic_info = None;
 
-    println!("- reading cbz file");
     let mut archive = ZipArchive::new(BufReader::new(file))?;
-    println!("- cbz file opened");
+    log::debug!("cbz file opened, {} entries", archive.len());
 
     let mut image_files = Vec::new();
 
@@ -47,7 +56,7 @@ pub fn extract_images_from_cbz<P: AsRef<Path>>(cbz_path: P) -> io::Result<(Vec<I
         };
 
         if !mime_type.starts_with("image/") && entry.name() != "ComicInfo.xml" {
-            println!("! skipping non-image file: {}", name);
+            log::warn!("skipping non-image file: {}", name);
             continue;
         }
 
@@ -68,18 +77,353 @@ pub fn extract_images_from_cbz<P: AsRef<Path>>(cbz_path: P) -> io::Result<(Vec<I
     Ok((image_files, comic_info))
 }
 
+/// What kindles can show. Anything else is converted
+const KINDLE_FORMATS: &[&str] = &["image/jpeg", "image/png", "image/gif"];
+
+/// Gets a page ready for the kindle: converted to JPEG or PNG if the kindle can't show it, borders
+/// cropped if `image.crop` says so, a double-page spread turned or split as `image.spreads` says,
+/// scaled down to fit `image.device`'s screen and, with `image.grayscale` unless the device has a
+/// color screen, turned gray and tuned for e-ink. Pages we can't decode are left as they are, unless the kindle couldn't show
+/// them either: leaving them out would send the chapter with pages missing, so that's an error
+pub fn prepare_page(page: ImageFile, config: &ImageConfig) -> Result<Vec<ImageFile>, String> {
+    let profile = config.profile().ok().flatten();
+    let gray = config.grayscale && profile.is_none_or(|p| !p.color);
+    let unsupported = !KINDLE_FORMATS.contains(&page.mime_type.as_str());
+    if profile.is_none() && !gray && !config.crop && config.spreads == Spreads::Keep && !unsupported {
+        return Ok(vec![page]);
+    }
+    // JPEG XL isn't built into `image`
+    static JXL: Once = Once::new();
+    JXL.call_once(|| {
+        jxl_oxide::integration::register_image_decoding_hook();
+    });
+    // and AVIF only with the avif feature, which needs libdav1d
+    let decoded = if cfg!(not(feature = "avif")) && page.mime_type == "image/avif" {
+        avifdec(&page.contents)
+    } else {
+        image::load_from_memory(&page.contents).map_err(|e| e.to_string())
+    };
+    let mut image = match decoded {
+        Ok(i) => i,
+        Err(e) if unsupported => {
+            return Err(format!("{} is {}, which the kindle can't show, and it couldn't be converted: {}", page.file_name, page.mime_type, e));
+        }
+        Err(e) => {
+            log::warn!("couldn't decode {}, using it as it is: {}", page.file_name, e);
+            return Ok(vec![page]);
+        }
+    };
+    let mut changed = unsupported;
+
+    if config.crop {
+        let (x, y, width, height) = content_bounds(&image.to_luma8(), config.crop_tolerance, config.crop_max);
+        if (width, height) != image.dimensions() {
+            log::debug!("cropped {} from {}x{} to {}x{}", page.file_name, image.width(), image.height(), width, height);
+            image = image.crop_imm(x, y, width, height);
+            changed = true;
+        }
+    }
+
+    // before scaling down, so the halves of a split spread keep their detail
+    let pieces = match spread(&image, config) {
+        Some(pieces) => {
+            log::debug!("{} is a double-page spread, made {} page(s) of it", page.file_name, pieces.len());
+            changed = true;
+            pieces
+        }
+        None => vec![("", image)],
+    };
+
+    let mut pages = Vec::new();
+    for (suffix, mut image) in pieces {
+        if let Some(profile) = profile.filter(|p| image.width() > p.width || image.height() > p.height) {
+            let resized = fit(&image, &profile);
+            log::debug!(
+                "resized {}{} from {}x{} to {}x{}",
+                page.file_name,
+                suffix,
+                image.width(),
+                image.height(),
+                resized.width(),
+                resized.height()
+            );
+            image = resized;
+            changed = true;
+        }
+        if gray {
+            image = DynamicImage::ImageLuma8(eink(&image, config));
+            changed = true;
+        }
+        if !changed {
+            return Ok(vec![page]);
+        }
+
+        match encode(&image, &page, suffix, config) {
+            Ok(p) => {
+                if unsupported {
+                    log::info!("converted {} from {} to {} ({})", page.file_name, page.mime_type, p.mime_type, p.file_name);
+                }
+                pages.push(p);
+            }
+            Err(e) if unsupported => {
+                return Err(format!("{} is {}, which the kindle can't show, and it couldn't be converted: {}", page.file_name, page.mime_type, e));
+            }
+            Err(e) => {
+                log::warn!("couldn't encode {}, using it as it is: {}", page.file_name, e);
+                return Ok(vec![page]);
+            }
+        }
+    }
+    Ok(pages)
+}
+
+/// Whether AVIF pages can be converted: with the avif feature, or with libavif's `avifdec`
+pub fn check_avif() -> Result<(), String> {
+    if cfg!(feature = "avif") {
+        return Ok(());
+    }
+    match Command::new("avifdec").arg("--version").output() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(no_avifdec(e)),
+    }
+}
+
+fn no_avifdec(e: io::Error) -> String {
+    format!("couldn't run avifdec ({}), install libavif or build kiyomi with `--features avif`", e)
+}
+
+/// Decodes an AVIF page with `avifdec`, for builds without the avif feature
+fn avifdec(contents: &[u8]) -> Result<DynamicImage, String> {
+    // workers convert side by side
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let base = std::env::temp_dir().join(format!("kiyomi-avif-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    let (input, output) = (base.with_extension("avif"), base.with_extension("png"));
+
+    let decode = || {
+        std::fs::write(&input, contents).map_err(|e| format!("{:?}: {}", input, e))?;
+        let result = Command::new("avifdec").arg(&input).arg(&output).output().map_err(no_avifdec)?;
+        if !result.status.success() {
+            let stderr = String::from_utf8_lossy(&result.stderr);
+            return Err(format!("avifdec failed with {}: {}", result.status, stderr.trim()));
+        }
+        let png = std::fs::read(&output).map_err(|e| format!("{:?}: {}", output, e))?;
+        image::load_from_memory(&png).map_err(|e| e.to_string())
+    };
+    let image = decode();
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+    image
+}
+
+/// The pages `image.spreads` makes of a page wider than tall, in reading order, each with what's
+/// added to its file name. `None` for single pages, or spreads that are kept as they are
+fn spread(image: &DynamicImage, config: &ImageConfig) -> Option<Vec<(&'static str, DynamicImage)>> {
+    let (width, height) = image.dimensions();
+    if width <= height || config.spreads == Spreads::Keep {
+        return None;
+    }
+
+    let left = image.crop_imm(0, 0, width / 2, height);
+    let right = image.crop_imm(width / 2, 0, width - width / 2, height);
+    // turned so the half that's read first is on top
+    let (first, second, rotated) = match config.direction.unwrap_or(Direction::Rtl) {
+        Direction::Rtl => (right, left, image.rotate270()),
+        Direction::Ltr => (left, right, image.rotate90()),
+    };
+
+    Some(match config.spreads {
+        Spreads::Keep => unreachable!(),
+        Spreads::Rotate => vec![("", rotated)],
+        Spreads::Split => vec![("-1", first), ("-2", second)],
+        Spreads::SplitRotate => vec![("-0", rotated), ("-1", first), ("-2", second)],
+    })
+}
+
+/// Where the art is, inside the uniform border around it: x, y, width and height. At most
+/// `max_percent` of the page is taken off each side
+fn content_bounds(gray: &GrayImage, tolerance: u8, max_percent: u8) -> (u32, u32, u32, u32) {
+    let (width, height) = gray.dimensions();
+    let row = |y: u32| (0..width).map(|x| gray.get_pixel(x, y).0[0]).collect::<Vec<_>>();
+    let column = |x: u32| (0..height).map(|y| gray.get_pixel(x, y).0[0]).collect::<Vec<_>>();
+
+    let max_x = width * max_percent as u32 / 100;
+    let max_y = height * max_percent as u32 / 100;
+    let top = border(row, max_y, tolerance);
+    let bottom = border(|n| row(height - 1 - n), max_y, tolerance);
+    let left = border(column, max_x, tolerance);
+    let right = border(|n| column(width - 1 - n), max_x, tolerance);
+
+    (left, top, width - left - right, height - top - bottom)
+}
+
+/// How many lines from the edge inwards are border: the color of the outermost line, give or
+/// take `tolerance`
+fn border(line: impl Fn(u32) -> Vec<u8>, max: u32, tolerance: u8) -> u32 {
+    let mut outermost = line(0);
+    outermost.sort_unstable();
+    let color = outermost[outermost.len() / 2];
+
+    let mut n = 0;
+    while n < max {
+        let pixels = line(n);
+        // a speck of dust doesn't make it art
+        let off = pixels.iter().filter(|p| p.abs_diff(color) > tolerance).count();
+        if off * 100 > pixels.len() {
+            break;
+        }
+        n += 1;
+    }
+    n
+}
+
+/// Scales an image down to fit the screen, keeping its aspect ratio
+fn fit(image: &DynamicImage, profile: &Profile) -> DynamicImage {
+    image.resize(profile.width, profile.height, FilterType::Lanczos3)
+}
+
+/// 8-bit gray with `image.autocontrast`, `image.gamma` and `image.quantize` applied
+fn eink(image: &DynamicImage, config: &ImageConfig) -> GrayImage {
+    let mut gray = image.to_luma8();
+
+    // the darkest and lightest grays, ignoring a few specks of dust
+    let (mut low, mut high) = (0, 255);
+    if config.autocontrast {
+        let mut histogram = [0u64; 256];
+        for p in gray.pixels() {
+            histogram[p.0[0] as usize] += 1;
+        }
+        let cutoff = gray.pixels().len() as u64 / 200;
+        low = past(histogram.iter(), cutoff);
+        high = 255 - past(histogram.iter().rev(), cutoff);
+        if high <= low {
+            (low, high) = (0, 255);
+        }
+    }
+
+    // one lookup for all of it
+    let table: Vec<u8> = (0..256)
+        .map(|v| {
+            let v = (v.clamp(low, high) - low) as f32 / (high - low) as f32;
+            let v = v.powf(config.gamma) * 255.0;
+            let v = if config.quantize { (v / 17.0).round() * 17.0 } else { v.round() };
+            v as u8
+        })
+        .collect();
+    for p in gray.pixels_mut() {
+        p.0[0] = table[p.0[0] as usize];
+    }
+    gray
+}
+
+/// The first bucket by which more than `cutoff` pixels were counted
+fn past<'a>(mut histogram: impl Iterator<Item = &'a u64>, cutoff: u64) -> usize {
+    let mut seen = 0;
+    histogram
+        .position(|n| {
+            seen += n;
+            seen > cutoff
+        })
+        .unwrap_or(0)
+}
+
+/// Whether an image is drawn rather than photographed or scanned: so few colors that PNG keeps it
+/// sharp and small
+fn line_art(image: &DynamicImage) -> bool {
+    let mut colors = std::collections::HashSet::new();
+    for p in image.to_rgb8().pixels() {
+        colors.insert(p.0);
+        if colors.len() > 256 {
+            return false;
+        }
+    }
+    true
+}
+
+/// JPEGs stay JPEG, PNGs and GIFs become PNGs. What the kindle can't show becomes a PNG if it's
+/// line art and a JPEG otherwise. `suffix` tells the pages made of one spread apart
+fn encode(image: &DynamicImage, page: &ImageFile, suffix: &str, config: &ImageConfig) -> image::ImageResult<ImageFile> {
+    let jpeg = match page.mime_type.as_str() {
+        "image/jpeg" => true,
+        "image/png" | "image/gif" => false,
+        _ => !line_art(image),
+    };
+
+    let mut contents = Vec::new();
+    let (format, extension) = if jpeg {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut contents, config.jpeg_quality);
+        // JPEG has no alpha channel
+        match image {
+            DynamicImage::ImageLuma8(gray) => gray.write_with_encoder(encoder)?,
+            _ => image.to_rgb8().write_with_encoder(encoder)?,
+        }
+        (ImageFormat::Jpeg, "jpg")
+    } else {
+        image.write_to(&mut io::Cursor::new(&mut contents), ImageFormat::Png)?;
+        (ImageFormat::Png, "png")
+    };
+
+    let file_name = match ImageFormat::from_path(&page.file_name) {
+        Ok(f) if f == format && suffix.is_empty() => page.file_name.clone(),
+        _ => {
+            let stem = page.file_name.rsplit_once('.').map_or(page.file_name.as_str(), |(stem, _)| stem);
+            format!("{}{}.{}", stem, suffix, extension)
+        }
+    };
+    Ok(ImageFile {
+        file_name,
+        contents,
+        mime_type: format.to_mime_type().to_string(),
+    })
+}
+
+/// Renames pages that ended up with the same file name, like a 002.webp converted next to a
+/// 002.jpg, so each has its own in the epub: 002-2.jpg
+pub fn unique_names(pages: &mut [ImageFile]) {
+    let mut taken = HashSet::new();
+    for page in pages {
+        if taken.insert(page.file_name.clone()) {
+            continue;
+        }
+        let (stem, extension) = match page.file_name.rsplit_once('.') {
+            Some((stem, extension)) => (stem, format!(".{}", extension)),
+            None => (page.file_name.as_str(), String::new()),
+        };
+        let name = (2..)
+            .map(|n| format!("{}-{}{}", stem, n, extension))
+            .find(|name| !taken.contains(name))
+            .unwrap();
+        log::debug!("renamed {} to {}, the name was taken", page.file_name, name);
+        taken.insert(name.clone());
+        page.file_name = name;
+    }
+}
+
 pub fn build_epub_from_images(
     manga: (&Vec<&ImageFile>, Option<ComicInfo>),
     cover_image: Option<&ImageFile>,
     fallback_title: &str,
     output_path: &str,
     file_of: Option<(usize, usize)>,
+    file_name: Option<&str>,
+    image_config: &ImageConfig,
 ) -> Result<String, Box<dyn std::error::Error>> {
 
     let (images, comic_info) = manga;
 
     let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
     epub.epub_version(EpubVersion::V30);
+    if let Some(profile) = image_config.profile()? {
+        epub.original_resolution(profile.width, profile.height);
+    }
+    if let Some(direction) = image_config.direction {
+        epub.metadata(
+            "direction",
+            match direction {
+                Direction::Rtl => "rtl",
+                Direction::Ltr => "ltr",
+            },
+        )?;
+    }
     
     // the first image is always the cover
     if let Some(cover_image) = cover_image {
@@ -97,7 +441,7 @@ pub fn build_epub_from_images(
     epub.metadata("author", comic_info.as_ref().and_then(|ci| ci.writer.as_deref()).unwrap_or("Unknown"))?;
 
     // output file! In the future there may be more than 1. We need to be under 50MB
-    let output_path = format!("{}/{}.epub", output_path, title);
+    let output_path = format!("{}/{}.epub", output_path, file_name.unwrap_or(&title));
     let mut output = File::create(&output_path)?;
 
     for (i, image_file) in images.iter().enumerate() {
@@ -135,8 +479,8 @@ pub fn build_epub_from_images(
         )?;
     }
 
-    for (_, image_file) in images.iter().enumerate() {
-        println!("+ adding image: {}", image_file.file_name);
+    for image_file in images.iter() {
+        log::trace!("adding image: {}", image_file.file_name);
         let image_path = format!("images/{}", image_file.file_name);
         epub.add_resource(&image_path, &image_file.contents[..], &image_file.mime_type)?;
     }
@@ -144,7 +488,7 @@ pub fn build_epub_from_images(
     // Write out the EPUB
     epub.generate(&mut output)?;
 
-    println!("- epub created at {} with {} pages", output_path, images.len());
+    log::info!("epub created at {} with {} pages", output_path, images.len());
 
     Ok(output_path)
 }
@@ -159,28 +503,23 @@ pub fn parse_comicinfo(xml_bytes: &[u8]) -> ComicInfo {
             Ok(quick_xml::events::Event::Start(ref e)) => {
                 match e.name().as_ref() {
                     b"Series" => {
-                        match reader.read_text(e.name()) {
-                            Ok(t) => {
-                                comic_info.series = Some(t.to_string());
-                            }
-                            _ => {}
+                        if let Ok(t) = reader.read_text(e.name()) {
+                            comic_info.series = Some(t.to_string());
                         }
                     }
                     b"Title" => {
-                        match reader.read_text(e.name()) {
-                            Ok(t) => {
-                                comic_info.title = Some(t.to_string());
-                            }
-                            _ => {}
+                        if let Ok(t) = reader.read_text(e.name()) {
+                            comic_info.title = Some(t.to_string());
+                        }
+                    }
+                    b"Number" => {
+                        if let Ok(t) = reader.read_text(e.name()) {
+                            comic_info.number = Some(t.to_string());
                         }
                     }
                     b"Writer" => {
-                        match reader.read_text(e.name()) {
-                            Ok(t) => {
-                                comic_info.writer = Some(t.to_string());
-                                println!("Writer: {:?}", t);
-                            }
-                            _ => {}
+                        if let Ok(t) = reader.read_text(e.name()) {
+                            comic_info.writer = Some(t.to_string());
                         }
                     }
                     _ => {}
@@ -188,7 +527,7 @@ pub fn parse_comicinfo(xml_bytes: &[u8]) -> ComicInfo {
             }
             Ok(quick_xml::events::Event::Eof) => break,
             Err(e) => {
-                eprintln!("! couldn't parse ComicInfo.xml: {:?} Basic metadata will be used", e);
+                log::warn!("couldn't parse ComicInfo.xml: {} Basic metadata will be used", e);
                 break;
             }
             _ => {}
@@ -198,3 +537,199 @@ pub fn parse_comicinfo(xml_bytes: &[u8]) -> ComicInfo {
 
     comic_info
 }
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+fn jpeg_page(width: u32, height: u32) -> ImageFile {
+    let mut contents = Vec::new();
+    DynamicImage::new_rgb8(width, height)
+        .write_to(&mut io::Cursor::new(&mut contents), ImageFormat::Jpeg)
+        .unwrap();
+    ImageFile {
+        file_name: "001.jpeg".into(),
+        contents,
+        mime_type: "image/jpeg".into(),
+    }
+}
+
+#[test]
+fn pages_are_fitted_to_the_screen() {
+    let paperwhite = ImageConfig {
+        device: Some("Paperwhite".into()),
+        ..Default::default()
+    };
+    assert_eq!(paperwhite.profile().unwrap().map(|p| (p.width, p.height)), Some((1236, 1648)));
+    assert!(ImageConfig { device: Some("kobo".into()), ..Default::default() }.profile().is_err());
+
+    let config = ImageConfig {
+        device: Some("300x400".into()),
+        grayscale: false,
+        ..Default::default()
+    };
+    let page = prepare_page(jpeg_page(600, 900), &config).unwrap().remove(0);
+    let image = image::load_from_memory(&page.contents).unwrap();
+    assert_eq!((image.width(), image.height()), (267, 400));
+    assert_eq!((page.file_name.as_str(), page.mime_type.as_str()), ("001.jpeg", "image/jpeg"));
+
+    // already small enough, not touched
+    let small = jpeg_page(200, 300);
+    let size = small.contents.len();
+    assert_eq!(prepare_page(small, &config).unwrap()[0].contents.len(), size);
+}
+
+#[test]
+fn gray_pages_are_tuned_for_e_ink() {
+    // a washed out page, grays 50 to 150
+    let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(100, 100, |x, _| {
+        let v = 50 + x as u8;
+        image::Rgb([v, v, v])
+    }));
+    let config = ImageConfig {
+        gamma: 1.0,
+        quantize: true,
+        ..Default::default()
+    };
+
+    let gray = eink(&image, &config);
+    let values: Vec<u8> = gray.pixels().map(|p| p.0[0]).collect();
+    assert_eq!((values.iter().min(), values.iter().max()), (Some(&0), Some(&255)));
+    assert!(values.iter().all(|v| v % 17 == 0));
+
+    // color screens get color
+    let colorsoft = ImageConfig {
+        device: Some("colorsoft".into()),
+        grayscale: true,
+        ..Default::default()
+    };
+    let small = jpeg_page(200, 300);
+    let size = small.contents.len();
+    assert_eq!(prepare_page(small, &colorsoft).unwrap()[0].contents.len(), size);
+
+    // no device, gray anyway
+    let config = ImageConfig {
+        grayscale: true,
+        ..Default::default()
+    };
+    let page = prepare_page(jpeg_page(200, 300), &config).unwrap().remove(0);
+    assert!(matches!(image::load_from_memory(&page.contents).unwrap(), DynamicImage::ImageLuma8(_)));
+}
+
+#[test]
+fn borders_are_cropped_up_to_the_cap() {
+    // art from 20 to 79 on a white page, a speck of dust in the top border
+    let mut page = GrayImage::from_fn(100, 100, |x, y| {
+        let art = (20..80).contains(&x) && (20..80).contains(&y);
+        image::Luma([if art { (x + y) as u8 } else { 250 }])
+    });
+    page.put_pixel(50, 5, image::Luma([0]));
+
+    assert_eq!(content_bounds(&page, 16, 45), (20, 20, 60, 60));
+    // no more than 10 off each side
+    assert_eq!(content_bounds(&page, 16, 10), (10, 10, 80, 80));
+
+    let mut config = crate::config::KiyomiConfig::default();
+    config.series.insert(
+        "Berserk".into(),
+        crate::config::SeriesConfig {
+            crop: Some(true),
+            ..Default::default()
+        },
+    );
+    assert!(config.image_for(&["Berserk"]).crop);
+    assert!(!config.image_for(&["Vagabond"]).crop);
+}
+
+#[test]
+fn spreads_are_split_in_reading_order() {
+    // left half black, right half white
+    let image = DynamicImage::ImageLuma8(GrayImage::from_fn(40, 20, |x, _| image::Luma([if x < 20 { 0 } else { 255 }])));
+    let first = |config: &ImageConfig| spread(&image, config).unwrap()[0].1.to_luma8().get_pixel(0, 0).0[0];
+
+    let mut config = ImageConfig {
+        spreads: Spreads::Split,
+        ..Default::default()
+    };
+    assert_eq!(first(&config), 255);
+    config.direction = Some(Direction::Ltr);
+    assert_eq!(first(&config), 0);
+
+    config.spreads = Spreads::SplitRotate;
+    let pages = spread(&image, &config).unwrap();
+    assert_eq!(pages.iter().map(|(_, p)| p.dimensions()).collect::<Vec<_>>(), [(20, 40), (20, 20), (20, 20)]);
+
+    // single pages and kept spreads stay as they are
+    assert!(spread(&image.rotate90(), &config).is_none());
+    config.spreads = Spreads::Keep;
+    assert!(spread(&image, &config).is_none());
+}
+
+#[test]
+fn unsupported_formats_are_converted() {
+    let webp = |image: DynamicImage| {
+        let mut contents = Vec::new();
+        image.write_to(&mut io::Cursor::new(&mut contents), ImageFormat::WebP).unwrap();
+        ImageFile {
+            file_name: "002.webp".into(),
+            contents,
+            mime_type: "image/webp".into(),
+        }
+    };
+    // nothing else asked for, only the conversion happens
+    let config = ImageConfig::default();
+
+    let scan = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 0])));
+    let mut pages = prepare_page(webp(scan), &config).unwrap();
+    assert_eq!((pages[0].file_name.as_str(), pages[0].mime_type.as_str()), ("002.jpg", "image/jpeg"));
+
+    // next to a 002.jpg that was there already
+    let mut chapter = vec![jpeg_page(10, 10), pages.remove(0)];
+    chapter[0].file_name = "002.jpg".into();
+    unique_names(&mut chapter);
+    assert_eq!((chapter[0].file_name.as_str(), chapter[1].file_name.as_str()), ("002.jpg", "002-2.jpg"));
+
+    let drawing = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, _| image::Luma([if x < 32 { 0 } else { 255 }])));
+    let pages = prepare_page(webp(drawing), &config).unwrap();
+    assert_eq!((pages[0].file_name.as_str(), pages[0].mime_type.as_str()), ("002.png", "image/png"));
+
+    let mut contents = Vec::new();
+    DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, _| image::Luma([if x < 32 { 0 } else { 255 }])))
+        .write_to(&mut io::Cursor::new(&mut contents), ImageFormat::Bmp).unwrap();
+    let bmp = ImageFile {
+        file_name: "005.bmp".into(),
+        contents,
+        mime_type: "image/bmp".into(),
+    };
+    assert_eq!(prepare_page(bmp, &config).unwrap()[0].file_name, "005.png");
+
+    // a tiny JPEG XL, from jxl-oxide's docs
+    let jxl = ImageFile {
+        file_name: "006.jxl".into(),
+        contents: vec![
+            0xff, 0x0a, 0x30, 0x54, 0x10, 0x09, 0x08, 0x06, 0x01, 0x00, 0x78, 0x00, 0x4b, 0x38, 0x41, 0x3c, 0xb6, 0x3a,
+            0x51, 0xfe, 0x00, 0x47, 0x1e, 0xa0, 0x85, 0xb8, 0x27, 0x1a, 0x48, 0x45, 0x84, 0x1b, 0x71, 0x4f, 0xa8, 0x3e,
+            0x8e, 0x30, 0x03, 0x92, 0x84, 0x01,
+        ],
+        mime_type: "image/jxl".into(),
+    };
+    let page = prepare_page(jxl, &config).unwrap().remove(0);
+    let image = image::load_from_memory(&page.contents).unwrap();
+    assert_eq!((page.file_name.as_str(), image.width(), image.height()), ("006.jpg", 240, 135));
+
+    // can't be read, and the kindle couldn't show it either
+    let broken = ImageFile {
+        file_name: "003.webp".into(),
+        contents: vec![0; 16],
+        mime_type: "image/webp".into(),
+    };
+    assert!(prepare_page(broken, &config).err().unwrap().contains("couldn't be converted"));
+
+    let avif = ImageFile {
+        file_name: "004.avif".into(),
+        contents: vec![0; 16],
+        mime_type: "image/avif".into(),
+    };
+    assert!(prepare_page(avif, &config).err().unwrap().contains("couldn't be converted"));
+}
@@ -0,0 +1,375 @@
+use notify::{Event, RecursiveMode};
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    batch::{self, Batcher},
+    config::{self, ConfigSource, KiyomiConfig},
+    convert, deadletter,
+    email::SharedMailer,
+    jobs::{JobState, JobStore},
+    limits::Limiter,
+    logging, pipeline, reload, retry, scan, watch, workdir,
+    worker::WorkerPool,
+};
+
+/// `kiyomi watch`: watch the manga directory and send everything new until we're stopped
+pub fn run(config_source: ConfigSource) -> Result<(), Box<dyn std::error::Error>> {
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+
+    log::info!("kiyomi - .cbz file watcher for kindle");
+
+    if let Ok(path) = config_source.path() {
+        log::info!("config file: {:?}", path);
+    }
+
+    let kiyomi_config = config::get_config(&config_source).map_err(|e| format!("config error: {}", e))?;
+    config::validate_config(&kiyomi_config).map_err(|e| format!("config error: {}", e))?;
+    logging::configure(&kiyomi_config.log).map_err(|e| format!("config error: {}", e))?;
+
+    // loaded once and swapped when the file changes. Each job works with the copy current when it started
+    let shared_config = reload::SharedConfig::new(kiyomi_config);
+    let kiyomi_config = shared_config.current();
+    let _config_watcher = match reload::watch_config(config_source.clone(), shared_config.clone()) {
+        Ok(w) => Some(w),
+        Err(e) => {
+            log::warn!("can't watch the config file, changes need a restart: {}", e);
+            None
+        }
+    };
+
+    // every file we've seen and how far it got
+    let mut job_store = JobStore::open().map_err(|e| format!("job store error: {}", e))?;
+    log::info!("job store: {:?}", job_store.path());
+    if kiyomi_config.options.dry_run {
+        log::info!("dry run: nothing is sent or deleted, and the job store is left as it is");
+        job_store = job_store.into_scratch();
+    }
+    let job_store = Arc::new(Mutex::new(job_store));
+
+    // one smtp connection pool for every job, rebuilt when [smtp] changes
+    let mailer = Arc::new(SharedMailer::default());
+    let limiter = Arc::new(Limiter::default());
+    if !kiyomi_config.options.dry_run {
+        match mailer.get(&kiyomi_config.smtp).and_then(|m| m.check()) {
+            Ok(_) => log::info!("smtp server {} is reachable", kiyomi_config.smtp.server),
+            Err(e) => log::warn!("can't reach the smtp server, sending will be retried: {}", e),
+        }
+    }
+
+    if let Err(e) = convert::check_avif() {
+        log::warn!("chapters with AVIF pages will fail: {}", e);
+    }
+
+    let watch_mode = watch::WatchMode::from_config(&kiyomi_config.watcher);
+    let mut debouncer = watch::Debouncer::new(watch_mode.quiet_period(&kiyomi_config.watcher));
+
+    let mut watcher = watch::new_watcher(watch_mode, tx)?;
+
+    let watch_dir = &kiyomi_config.directories.manga;
+    watcher.watch(watch_dir, RecursiveMode::Recursive)?;
+
+    // conversion and sending happen here, off the event loop
+    let workers = kiyomi_config.options.workers.max(1);
+    let batcher = if kiyomi_config.batch.enabled {
+        log::info!("batching epubs into emails of up to {} MB", kiyomi_config.options.size_limit);
+        Some(Batcher::start(mailer.clone(), limiter.clone())?)
+    } else {
+        None
+    };
+    let outgoing = Arc::new(Outgoing { mailer, limiter, batcher });
+    let pool = {
+        let job_store = job_store.clone();
+        let shared_config = shared_config.clone();
+        let outgoing = outgoing.clone();
+        WorkerPool::new(workers, move |path| {
+            let run = || process_job(path, &job_store, &shared_config.current(), &outgoing);
+            if let Err(panic) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(run)) {
+                // the job store may have been locked when it happened, it is still usable
+                job_store.clear_poison();
+                record_state(&job_store, path, JobState::Failed);
+                // the pool logs it
+                std::panic::resume_unwind(panic);
+            }
+        })
+    };
+    log::info!("{} worker(s)", workers);
+
+    // anything we were in the middle of when we last stopped gets another go
+    let unfinished = job_store.lock().unwrap().unfinished();
+    if !unfinished.is_empty() {
+        log::info!("resuming {} unfinished job(s)", unfinished.len());
+    }
+    for path in unfinished {
+        if !path.is_file() {
+            log::warn!("{:?} is gone, marking it as failed", path);
+            record_state(&job_store, &path, JobState::Failed);
+            continue;
+        }
+        pool.submit(path);
+    }
+
+    // manga that was downloaded while we weren't running
+    if kiyomi_config.options.backfill {
+        let max_age = kiyomi_config
+            .options
+            .backfill_days
+            .filter(|d| *d > 0)
+            .map(|d| Duration::from_secs(d * 24 * 60 * 60));
+
+        let found = scan::backfill(watch_dir, &job_store.lock().unwrap(), max_age);
+        match found {
+            Ok(files) => {
+                log::info!("backfill found {} new file(s)", files.len());
+                process_new_manga(files, &job_store, &pool);
+            }
+            Err(e) => log::error!("backfill error: {}", e),
+        }
+    }
+
+    log::info!("watching for new manga.cbz in {:?} ({:?})", watch_dir, watch_mode);
+
+    loop {
+        match rx.recv_timeout(debouncer.timeout()) {
+            Ok(Ok(event)) => debouncer.event(event),
+            Ok(Err(e)) => log::error!("watch error: {}", e),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let ready = debouncer.ready();
+        if !ready.is_empty() {
+            process_new_manga(ready, &job_store, &pool);
+        }
+    }
+
+    pool.finish();
+    // the workers are gone, so are their references to it
+    if let Ok(Outgoing { batcher: Some(batcher), .. }) = Arc::try_unwrap(outgoing) {
+        batcher.finish();
+    }
+
+    Ok(())
+}
+
+/// Files were created! Let's check if they're .cbz files. If they are, we'll hand them to the workers.
+fn process_new_manga(paths: Vec<PathBuf>, job_store: &Mutex<JobStore>, pool: &WorkerPool) {
+    for path in paths {
+        if !path.is_file() {
+            continue;
+        }
+        if path.extension().unwrap_or_default() != "cbz" {
+            continue;
+        }
+
+        let queued = job_store.lock().unwrap().enqueue(&path);
+        match queued {
+            Ok(true) => (),
+            Ok(false) => {
+                log::debug!("already processed, skipping: {:?}", path);
+                continue;
+            }
+            Err(e) => {
+                log::error!("job store error: {}", e);
+                continue;
+            }
+        }
+
+        log::info!("found new file: {:?}", path);
+        pool.submit(path);
+    }
+}
+
+/// What every job sends its epubs with
+pub struct Outgoing {
+    pub mailer: Arc<SharedMailer>,
+    pub limiter: Arc<Limiter>,
+    /// with `batch.enabled`, epubs wait here to share an email with others
+    pub batcher: Option<Batcher>,
+}
+
+/// Runs a queued job and records the outcome. Called on a worker thread. When batching, the job
+/// is finished on the batcher's thread once its last part went out.
+pub fn process_job(
+    path: &Path,
+    job_store: &Arc<Mutex<JobStore>>,
+    kiyomi_config: &Arc<KiyomiConfig>,
+    outgoing: &Outgoing,
+) {
+    let _scope = logging::job_scope(path);
+
+    let (job_dir, parts) = match convert(path, job_store, kiyomi_config) {
+        Ok(converted) => converted,
+        Err(e) => {
+            log::error!("job failed: {}", e);
+            record_state(job_store, path, JobState::Failed);
+            return;
+        }
+    };
+
+    let delivery = Delivery {
+        path: path.to_path_buf(),
+        job_store: job_store.clone(),
+        kiyomi_config: kiyomi_config.clone(),
+        job_dir,
+        total: parts.len(),
+        outstanding: parts.len(),
+        failed: Vec::new(),
+    };
+
+    let Some(batcher) = &outgoing.batcher else {
+        let mut delivery = delivery;
+        for part in &parts {
+            let _part = logging::part_scope(part.index, part.total);
+            let result = pipeline::send(part, kiyomi_config, &outgoing.mailer, &outgoing.limiter);
+            let (subject, body) = (pipeline::subject(part, kiyomi_config), pipeline::body(part, kiyomi_config));
+            delivery.part_done(&part.path, subject, body, result);
+        }
+        delivery.finish();
+        return;
+    };
+
+    let delivery = Arc::new(Mutex::new(Some(delivery)));
+    for part in &parts {
+        let subject = pipeline::subject(part, kiyomi_config);
+        let body = pipeline::body(part, kiyomi_config);
+        let delivery = delivery.clone();
+        let epub = part.path.clone();
+        let (done_subject, done_body) = (subject.clone(), body.clone());
+        let done: batch::Done = Box::new(move |result| {
+            let mut delivery = delivery.lock().unwrap();
+            let Some(d) = delivery.as_mut() else { return };
+            let _scope = logging::job_scope(&d.path);
+            d.part_done(&epub, done_subject, done_body, result);
+            if d.outstanding == 0 {
+                if let Some(d) = delivery.take() {
+                    d.finish();
+                }
+            }
+        });
+        batcher.submit(&part.path, &part.chapter.series, &subject, &body, kiyomi_config.clone(), done);
+    }
+}
+
+/// Deletes a .cbz that made it to the kindle, if `options.delete` says so
+pub fn delete_sent(path: &Path, kiyomi_config: &KiyomiConfig) {
+    if !kiyomi_config.options.delete {
+        return;
+    }
+    if kiyomi_config.options.dry_run {
+        log::info!("[dry run] would delete {:?}", path);
+    } else {
+        match std::fs::remove_file(path) {
+            Ok(_) => log::info!("deleted file: {:?}", path),
+            Err(e) => log::error!("couldn't delete file: {}", e),
+        }
+    }
+}
+
+/// We found a cbz manga. Let's build its epubs, in a work directory of its own.
+fn convert(
+    path: &Path,
+    job_store: &Mutex<JobStore>,
+    kiyomi_config: &KiyomiConfig,
+) -> Result<(workdir::JobDir, Vec<pipeline::Part>), Box<dyn std::error::Error>> {
+    job_store.lock().unwrap().set_state(path, JobState::Converting)?;
+
+    // this job's own scratch space, outside the watched tree. A dry run keeps it for you to look at
+    let root = if kiyomi_config.options.dry_run {
+        workdir::dry_run_root(&kiyomi_config.options, &kiyomi_config.directories)?
+    } else {
+        workdir::work_root(&kiyomi_config.directories)?
+    };
+    let job_dir = workdir::JobDir::create(&root, path)?;
+
+    let parts = match pipeline::convert(path, kiyomi_config, job_dir.path()) {
+        Ok(p) => p,
+        Err(e) => {
+            log::warn!("kept {:?} for inspection", job_dir.path());
+            return Err(e);
+        }
+    };
+
+    job_store.lock().unwrap().set_state(path, JobState::Sending)?;
+    Ok((job_dir, parts))
+}
+
+/// A converted job whose parts are being sent
+struct Delivery {
+    path: PathBuf,
+    job_store: Arc<Mutex<JobStore>>,
+    kiyomi_config: Arc<KiyomiConfig>,
+    job_dir: workdir::JobDir,
+    total: usize,
+    /// parts we haven't heard back about yet
+    outstanding: usize,
+    failed: Vec<(PathBuf, deadletter::FailedPart)>,
+}
+
+impl Delivery {
+    fn part_done(&mut self, epub: &Path, subject: String, body: String, result: Result<(), retry::Failure>) {
+        self.outstanding -= 1;
+        match result {
+            // a dry run already logged what it would have sent
+            Ok(_) if self.kiyomi_config.options.dry_run => {}
+            Ok(_) => log::info!("email sent"),
+            Err(f) => {
+                log::error!("email error after {} attempt(s): {}", f.attempts, f.error);
+                let report = deadletter::FailedPart {
+                    file: epub.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                    subject,
+                    body,
+                    attempts: f.attempts,
+                    error: f.error.to_string(),
+                };
+                self.failed.push((epub.to_path_buf(), report));
+            }
+        }
+    }
+
+    /// Cleans up after the last part and records how the job went
+    fn finish(self) {
+        let kiyomi_config = &self.kiyomi_config;
+
+        let state = if self.failed.is_empty() {
+            if kiyomi_config.options.dry_run {
+                log::info!("[dry run] epubs are in {:?}", self.job_dir.path());
+            } else if let Err(e) = self.job_dir.remove() {
+                log::warn!("couldn't remove work directory: {}", e);
+            }
+            JobState::Sent
+        } else {
+            let count = self.failed.len();
+            // parts that did go out aren't sent again, only the failed ones are kept for `kiyomi resend`
+            let dead_letter = workdir::dead_letter_root(&kiyomi_config.directories).and_then(|root| {
+                Ok(deadletter::Entry::create(&root, self.job_dir.path(), &self.path, self.failed)?)
+            });
+            match dead_letter {
+                Ok(entry) => {
+                    log::warn!("moved the failed epub(s) to {:?}, `kiyomi resend` tries them again", entry.dir);
+                    if let Err(e) = self.job_dir.remove() {
+                        log::warn!("couldn't remove work directory: {}", e);
+                    }
+                }
+                Err(e) => log::error!("couldn't move the failed epub(s) to the dead-letter directory, kept {:?}: {}", self.job_dir.path(), e),
+            }
+            log::error!("job failed: {} of {} part(s) failed", count, self.total);
+            JobState::Failed
+        };
+        record_state(&self.job_store, &self.path, state);
+
+        // delete if desired, but never something that didn't make it to the kindle
+        if state == JobState::Sent {
+            delete_sent(&self.path, kiyomi_config);
+        }
+    }
+}
+
+fn record_state(job_store: &Mutex<JobStore>, path: &Path, state: JobState) {
+    if let Err(e) = job_store.lock().unwrap().set_state(path, state) {
+        log::error!("job store error: {}", e);
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// An epub that couldn't be sent, and why
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedPart {
+    /// file name of the epub, next to the report
+    pub file: String,
+    pub subject: String,
+    /// reports from before `[templates]` don't have one
+    #[serde(default = "default_body")]
+    pub body: String,
+    /// how many times it was tried so far
+    pub attempts: u32,
+    pub error: String,
+}
+
+/// `report.toml` of a dead-letter entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    /// the .cbz the epubs were built from
+    pub source: PathBuf,
+    /// seconds since the epoch of the last failed attempt
+    pub failed_at: u64,
+    pub parts: Vec<FailedPart>,
+}
+
+/// One chapter's worth of epubs that couldn't be sent: a directory in the dead-letter directory
+/// holding the epubs and a `report.toml` saying where they came from and what went wrong.
+/// `kiyomi resend` without arguments tries them again.
+pub struct Entry {
+    pub dir: PathBuf,
+    pub report: Report,
+}
+
+const REPORT: &str = "report.toml";
+
+fn default_body() -> String {
+    crate::config::TemplatesConfig::default().body
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Entry {
+    /// Moves the failed epubs out of `job_dir` into a new entry named like it
+    pub fn create(root: &Path, job_dir: &Path, source: &Path, failed: Vec<(PathBuf, FailedPart)>) -> io::Result<Entry> {
+        let name = job_dir.file_name().ok_or_else(|| io::Error::other("job directory has no name"))?;
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir)?;
+
+        let mut parts = Vec::new();
+        for (epub, part) in failed {
+            let target = dir.join(&part.file);
+            // the work dir may be on another filesystem
+            if std::fs::rename(&epub, &target).is_err() {
+                std::fs::copy(&epub, &target)?;
+                std::fs::remove_file(&epub)?;
+            }
+            parts.push(part);
+        }
+
+        let entry = Entry {
+            dir,
+            report: Report {
+                source: source.to_path_buf(),
+                failed_at: now(),
+                parts,
+            },
+        };
+        entry.save()?;
+        Ok(entry)
+    }
+
+    /// Writes the report after the parts were changed
+    pub fn save(&self) -> io::Result<()> {
+        let report = toml::to_string(&self.report).map_err(io::Error::other)?;
+        let tmp = self.dir.join(format!("{}.tmp", REPORT));
+        std::fs::write(&tmp, report)?;
+        std::fs::rename(&tmp, self.dir.join(REPORT))
+    }
+
+    /// Call after another failed attempt
+    pub fn touch(&mut self) {
+        self.report.failed_at = now();
+    }
+
+    pub fn remove(self) -> io::Result<()> {
+        std::fs::remove_dir_all(&self.dir)
+    }
+}
+
+/// Every entry in the dead-letter directory, oldest first. Directories without a readable report
+/// are skipped with a warning.
+pub fn entries(root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let dirs = match std::fs::read_dir(root) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e),
+    };
+
+    for dir in dirs {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        let report = std::fs::read_to_string(dir.join(REPORT))
+            .map_err(|e| e.to_string())
+            .and_then(|r| toml::from_str::<Report>(&r).map_err(|e| e.to_string()));
+        match report {
+            Ok(report) => entries.push(Entry { dir, report }),
+            Err(e) => log::warn!("skipping {:?}, its {} can't be read: {}", dir, REPORT, e),
+        }
+    }
+
+    entries.sort_by(|a, b| a.report.failed_at.cmp(&b.report.failed_at).then(a.dir.cmp(&b.dir)));
+    Ok(entries)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn entries_round_trip() {
+    let root = crate::workdir::TempDir::new("dead-letter");
+    let job_dir = root.join("work/1-2-3-chapter");
+    std::fs::create_dir_all(&job_dir).unwrap();
+    std::fs::write(job_dir.join("a.epub"), "epub").unwrap();
+
+    let failed = vec![(
+        job_dir.join("a.epub"),
+        FailedPart {
+            file: "a.epub".into(),
+            subject: "1-2 Manga".into(),
+            body: "Here's your manga!".into(),
+            attempts: 4,
+            error: "connection refused".into(),
+        },
+    )];
+    Entry::create(&root.join("dead"), &job_dir, Path::new("/manga/x/chapter.cbz"), failed).unwrap();
+    assert!(!job_dir.join("a.epub").exists());
+
+    let entries = entries(&root.join("dead")).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].report.source, Path::new("/manga/x/chapter.cbz"));
+    assert_eq!(entries[0].report.parts[0].subject, "1-2 Manga");
+    assert!(entries[0].dir.join("a.epub").is_file());
+}
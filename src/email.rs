@@ -1,74 +1,314 @@
 use lettre::message::header;
 use lettre::message::{Attachment, Mailbox, Message, MultiPart, SinglePart};
-use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Certificate, Tls, TlsParameters};
 use lettre::{SmtpTransport, Transport};
 use std::error::Error;
 use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-/// Sends an EPUB file as an email attachment.
-pub fn send_epub(
-    port: Option<i64>, // optional port, default is 587 for TLS
-    smtp_server: &str,
-    smtp_username: &str,
-    smtp_password: &str,
-    from_email: &str,
-    to_email: &str,
-    subject: &str,
-    epub_path: &str, // all the epubs are in this directory
-) -> Result<(), Box<dyn Error>> {
-
-    // get the file
-    let epub_bytes = fs::read(epub_path)?;
-    let epub_filename = epub_path
-        .rsplit('/')
-        .next()
-        .unwrap_or("attachment.epub"); // fallback name
+use crate::config::{SmtpAuth, SmtpConfig, SmtpSecurity};
+use crate::oauth2;
 
-    // build the email message
+/// The way to the SMTP server, built once from `[smtp]`. Connections are pooled and reused, so
+/// the parts of a chapter and the chapters after it don't each pay for a TLS handshake and a
+/// login. A connection that went stale is noticed when it's taken from the pool and replaced.
+pub struct Mailer {
+    smtp: SmtpConfig,
+    /// the XOAUTH2 access token the transport logs in with
+    token: Option<String>,
+    transport: SmtpTransport,
+}
+
+impl Mailer {
+    pub fn new(smtp: &SmtpConfig) -> Result<Mailer, Box<dyn Error>> {
+        let token = match smtp.auth {
+            SmtpAuth::Password => None,
+            SmtpAuth::Xoauth2 => Some(oauth2::access_token(&smtp.oauth2, timeout(smtp))?),
+        };
+
+        let credentials = match &token {
+            Some(token) => Some(Credentials::new(smtp.username.clone(), token.clone())),
+            // local relays often take mail from anyone on the network
+            None if smtp.username.is_empty() => None,
+            None => Some(Credentials::new(smtp.username.clone(), smtp.password.clone())),
+        };
+
+        // a transport that can't be built is a config problem, not worth retrying
+        let transport = transport(smtp, credentials).map_err(|e| format!("smtp settings: {}", e))?;
+
+        Ok(Mailer {
+            smtp: smtp.clone(),
+            token,
+            transport,
+        })
+    }
+
+    /// Connects and says hello, to find out early whether the server can be reached
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        match self.transport.test_connection()? {
+            true => Ok(()),
+            false => Err("the server didn't answer".into()),
+        }
+    }
+
+    /// Sends EPUB files as attachments of one email.
+    pub fn send_epubs(&self, subject: &str, body: &str, epubs: &[&Path]) -> Result<(), Box<dyn Error>> {
+        let smtp = &self.smtp;
+        let email = message(smtp, subject, body, epubs)?;
+
+        let result = match self.transport.send(&email) {
+            // the connection broke while we were using it. The pool has dropped it, one more
+            // go gets a fresh one. An answer from the server, yes or no, is final
+            Err(e) if !e.is_response() && !e.is_client() && !e.is_transient() && !e.is_permanent() => {
+                log::debug!("smtp connection lost, reconnecting: {}", e);
+                self.transport.send(&email)
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) => {
+                Ok(())
+            }
+            Err(e) => {
+                log::debug!("smtp error: {:?}", e);
+                // the token may have been revoked early, get a new one next time
+                if smtp.auth == SmtpAuth::Xoauth2 && is_login_refused(&e) {
+                    oauth2::forget(&smtp.oauth2);
+                }
+                Err(Box::new(e))
+            }
+        }
+    }
+}
+
+/// The current `Mailer`. Shared by every job and replaced when the `[smtp]` settings change or,
+/// for XOAUTH2, when the access token it logs in with runs out.
+#[derive(Default)]
+pub struct SharedMailer(Mutex<Option<Arc<Mailer>>>);
+
+impl SharedMailer {
+    pub fn get(&self, smtp: &SmtpConfig) -> Result<Arc<Mailer>, Box<dyn Error>> {
+        let mut current = self.0.lock().unwrap();
+
+        if let Some(mailer) = current.as_ref() {
+            let token = match smtp.auth {
+                SmtpAuth::Password => None,
+                SmtpAuth::Xoauth2 => Some(oauth2::access_token(&smtp.oauth2, timeout(smtp))?),
+            };
+            if mailer.smtp == *smtp && mailer.token == token {
+                return Ok(mailer.clone());
+            }
+            if mailer.smtp != *smtp {
+                log::info!("smtp settings changed, reconnecting");
+            }
+        }
+
+        let mailer = Arc::new(Mailer::new(smtp)?);
+        *current = Some(mailer.clone());
+        Ok(mailer)
+    }
+
+    /// Sends with the current `Mailer`. Under XOAUTH2 a refused login may only mean that the
+    /// access token was revoked before it ran out, so that gets one more go with a new token.
+    pub fn send(&self, smtp: &SmtpConfig, subject: &str, body: &str, epubs: &[&Path]) -> Result<(), Box<dyn Error>> {
+        let refused = |e: &(dyn Error + 'static)| {
+            e.downcast_ref::<lettre::transport::smtp::Error>()
+                .is_some_and(is_login_refused)
+        };
+        match self.get(smtp)?.send_epubs(subject, body, epubs) {
+            Err(e) if smtp.auth == SmtpAuth::Xoauth2 && refused(e.as_ref()) => {
+                log::debug!("trying again with a new access token: {}", e);
+                self.get(smtp)?.send_epubs(subject, body, epubs)
+            }
+            result => result,
+        }
+    }
+}
+
+/// 530, 534 and 535: the server didn't take our credentials. Any other refusal (a bad
+/// recipient, a message too large) has nothing to do with the token
+fn is_login_refused(error: &lettre::transport::smtp::Error) -> bool {
+    error.status().is_some_and(|code| matches!(u16::from(code), 530 | 534 | 535))
+}
+
+fn message(smtp: &SmtpConfig, subject: &str, text: &str, epubs: &[&Path]) -> Result<Message, Box<dyn Error>> {
     // "multipart/mixed" content type allows us to include attachments
+    let mut body = MultiPart::mixed().singlepart(
+        SinglePart::builder()
+            .header(header::ContentType::TEXT_PLAIN)
+            .body(text.to_string())
+    );
+
+    for epub_path in epubs {
+        // get the file
+        let epub_bytes = fs::read(epub_path)?;
+        let epub_filename = epub_path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or("attachment.epub"); // fallback name
+
+        body = body.singlepart(
+            Attachment::new(String::from(epub_filename))
+                .body(epub_bytes, "application/epub+zip".parse()?)
+        );
+    }
+
+    // build the email message
     let email = Message::builder()
-        .from(from_email.parse::<Mailbox>()?)
-        .to(to_email.parse::<Mailbox>()?)
+        .from(smtp.from_email.parse::<Mailbox>()?)
+        .to(smtp.to_email.parse::<Mailbox>()?)
         .subject(subject)
-        .multipart(
-            MultiPart::mixed()
-                .singlepart(
-                    SinglePart::builder()
-                        .header(header::ContentType::TEXT_PLAIN)
-                        .body(String::from("Here's your manga!"))
-                )
-                .singlepart(
-                    Attachment::new(String::from(epub_filename))
-                        .body(epub_bytes, "application/epub+zip".parse()?)
-                )
-        )?;
-
-    // create the smtp transporter
-    //  - for gmail and other servers that require TLS port 587 is usually used
-    //  - for local/insecure servers adapt accordingly ?
-    let creds = Credentials::new(smtp_username.to_string(), smtp_password.to_string());
-
-    let mailer = match port {
-        Some(p) => {
-            SmtpTransport::relay(smtp_server)?
-                .port(p as u16)
-                .credentials(creds)
-                .build()
-        }
-        None => {
-            SmtpTransport::relay(smtp_server)?
-                .credentials(creds)
-                .build()
+        .multipart(body)?;
+
+    Ok(email)
+}
+
+/// Roughly how many bytes an attachment of `bytes` takes up in the email: base64 turns every
+/// 3 bytes into 4 and breaks lines every 76 characters. Plus some room for its headers
+pub fn encoded_size(bytes: u64) -> u64 {
+    let base64 = bytes.div_ceil(3) * 4;
+    base64 + base64.div_ceil(76) * 2 + 512
+}
+
+fn timeout(smtp: &SmtpConfig) -> Duration {
+    Duration::from_secs(smtp.timeout.unwrap_or(60))
+}
+
+/// Builds the transport `smtp` describes: how the connection is secured, which certificates are
+/// trusted and how we log in.
+fn transport(smtp: &SmtpConfig, credentials: Option<Credentials>) -> Result<SmtpTransport, Box<dyn Error>> {
+    let tls = match smtp.security {
+        SmtpSecurity::None => Tls::None,
+        security => {
+            let mut params = TlsParameters::builder(smtp.server.clone())
+                .dangerous_accept_invalid_certs(smtp.accept_invalid_certs);
+            if let Some(ca_file) = &smtp.ca_file {
+                let pem = fs::read(ca_file).map_err(|e| format!("smtp.ca_file {:?}: {}", ca_file, e))?;
+                params = params.add_root_certificate(Certificate::from_pem(&pem)?);
+            }
+            let params = params.build()?;
+            if security == SmtpSecurity::Tls {
+                Tls::Wrapper(params)
+            } else {
+                // never fall back to plain text if the server doesn't offer STARTTLS
+                Tls::Required(params)
+            }
         }
     };
 
-    match mailer.send(&email) {
-        Ok(_) => {
-            Ok(())
-        }
-        Err(e) => {
-            eprintln!("Could not send email: {:?}", e);
-            Err(Box::new(e))
-        }
+    let mut builder = SmtpTransport::builder_dangerous(smtp.server.as_str())
+        .port(smtp.port.unwrap_or(smtp.security.default_port()))
+        .tls(tls)
+        .timeout(Some(timeout(smtp)));
+
+    if let Some(credentials) = credentials {
+        builder = builder.credentials(credentials);
     }
+    if smtp.auth == SmtpAuth::Xoauth2 {
+        builder = builder.authentication(vec![Mechanism::Xoauth2]);
+    }
+
+    Ok(builder.build())
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn xoauth2_login() {
+    use std::io::{BufRead, BufReader, Write};
+
+    let dir = crate::workdir::TempDir::new("xoauth2");
+    let epub = dir.join("chapter.epub");
+    std::fs::write(&epub, "epub").unwrap();
+
+    let (token_url, token_server) = oauth2::mock_token_endpoint(vec![
+        (200, r#"{"access_token":"at-1","expires_in":3600}"#),
+        (200, r#"{"access_token":"at-2","expires_in":3600}"#),
+    ]);
+
+    // just enough SMTP to take one mail, remembering what the client said. The first login is
+    // refused as if the token had been revoked
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let smtp_server = std::thread::spawn(move || {
+        let mut commands = Vec::new();
+        for login in ["535 5.7.8 token revoked\r\n", "235 ok\r\n"] {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            stream.write_all(b"220 mock\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = match line.split(' ').next().unwrap().to_uppercase().as_str() {
+                    "EHLO" => b"250-mock\r\n250 AUTH XOAUTH2\r\n",
+                    "AUTH" => login.as_bytes(),
+                    "DATA" => {
+                        stream.write_all(b"354 go\r\n").unwrap();
+                        let mut body = String::new();
+                        while body != ".\r\n" {
+                            body.clear();
+                            reader.read_line(&mut body).unwrap();
+                        }
+                        b"250 ok\r\n"
+                    }
+                    "QUIT" => {
+                        let _ = stream.write_all(b"221 bye\r\n");
+                        commands.push(line);
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                commands.push(line);
+                if stream.write_all(reply).is_err() {
+                    break;
+                }
+            }
+        }
+        commands
+    });
+
+    let smtp = SmtpConfig {
+        server: "127.0.0.1".into(),
+        port: Some(port),
+        security: SmtpSecurity::None,
+        username: "me@example.com".into(),
+        from_email: "me@example.com".into(),
+        to_email: "kindle@example.com".into(),
+        timeout: Some(5),
+        auth: SmtpAuth::Xoauth2,
+        oauth2: crate::config::OAuth2Config {
+            token_url,
+            client_id: "kiyomi".into(),
+            refresh_token: "rt-1".into(),
+            token_file: Some(dir.join("token")),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mailer = SharedMailer::default();
+    mailer.send(&smtp, "Manga", "Here's your manga!", &[&epub]).unwrap();
+    drop(mailer);
+
+    token_server.join().unwrap();
+    let commands = smtp_server.join().unwrap();
+    // base64 of "user=me@example.com\x01auth=Bearer at-1\x01\x01", then the same with at-2
+    let logins: Vec<&String> = commands.iter().filter(|c| c.starts_with("AUTH")).collect();
+    assert_eq!(
+        logins,
+        [
+            "AUTH XOAUTH2 dXNlcj1tZUBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciBhdC0xAQE=",
+            "AUTH XOAUTH2 dXNlcj1tZUBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciBhdC0yAQE=",
+        ]
+    );
+    assert!(commands.iter().any(|c| c == "DATA"), "{:?}", commands);
 }
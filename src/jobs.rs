@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::scan;
+
+/// Where a discovered .cbz is in the pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Converting,
+    Sending,
+    Sent,
+    Failed,
+}
+
+impl JobState {
+    /// true while the job still has work left to do (it gets resumed on restart)
+    pub fn is_unfinished(self) -> bool {
+        matches!(self, JobState::Queued | JobState::Converting | JobState::Sending)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Converting => "converting",
+            JobState::Sending => "sending",
+            JobState::Sent => "sent",
+            JobState::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Option<JobState> {
+        match s {
+            "queued" => Some(JobState::Queued),
+            "converting" => Some(JobState::Converting),
+            "sending" => Some(JobState::Sending),
+            "sent" => Some(JobState::Sent),
+            "failed" => Some(JobState::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Size and modification time of a file when we picked it up. Lets us tell a file that was
+/// overwritten in place from one we have already sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    len: u64,
+    modified: u128,
+}
+
+impl Fingerprint {
+    pub fn of<P: AsRef<Path>>(file: P) -> Option<Fingerprint> {
+        let metadata = std::fs::metadata(file).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Fingerprint {
+            len: metadata.len(),
+            modified: modified.as_nanos(),
+        })
+    }
+
+    fn parse(s: &str) -> Option<Fingerprint> {
+        let (len, modified) = s.split_once(':')?;
+        Some(Fingerprint {
+            len: len.parse().ok()?,
+            modified: modified.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.len, self.modified)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Job {
+    state: JobState,
+    fingerprint: Option<Fingerprint>,
+}
+
+impl Job {
+    fn line(&self, file: &str) -> String {
+        match self.fingerprint {
+            Some(fp) => format!("{}\t{}\t{}\n", self.state, fp, file),
+            None => format!("{}\t-\t{}\n", self.state, file),
+        }
+    }
+
+    /// `<state>\t<fingerprint>\t<path>`, or `<state>\t<path>` from before fingerprints existed
+    fn parse(line: &str) -> Option<(PathBuf, Job)> {
+        let (state, rest) = line.split_once('\t')?;
+        let state = JobState::parse(state)?;
+
+        let (fingerprint, file) = match rest.split_once('\t') {
+            Some(("-", file)) => (None, file),
+            Some((fp, file)) if Fingerprint::parse(fp).is_some() => (Fingerprint::parse(fp), file),
+            _ => (None, rest),
+        };
+
+        Some((PathBuf::from(file), Job { state, fingerprint }))
+    }
+}
+
+/// Durable record of every .cbz kiyomi has seen.
+///
+/// Backed by an append-only journal: every state change is one `<state>\t<fingerprint>\t<path>`
+/// line, synced to disk before we act on it. The last line for a path wins. On open the journal
+/// is replayed and compacted, so a crash at any point leaves at worst one torn trailing line,
+/// which is ignored.
+///
+/// Several processes may have the store open (the daemon and `kiyomi resend`, say). They share
+/// a lock on `<journal>.lock`, and compaction only happens when nobody else holds it.
+pub struct JobStore {
+    path: PathBuf,
+    /// None for a scratch store, see `into_scratch`
+    journal: Option<File>,
+    jobs: HashMap<PathBuf, Job>,
+    _lock: Option<File>,
+}
+
+impl JobStore {
+    /// Opens the store in the default location (`~/.cache/kiyomi/jobs`).
+    /// Paths from the old plain-text `cache` log are imported as sent.
+    pub fn open() -> Result<JobStore, Box<dyn std::error::Error>> {
+        let dir = dirs::cache_dir()
+            .ok_or("the cache directory could not be found")?
+            .join("kiyomi");
+        std::fs::create_dir_all(&dir)?;
+
+        let path = dir.join("jobs");
+        let legacy = dir.join("cache");
+        let mut store = JobStore::open_at(&path)?;
+
+        if store.jobs.is_empty() && legacy.exists() {
+            let old = std::fs::read_to_string(&legacy)?;
+            for line in old.lines().filter(|l| !l.is_empty()) {
+                store.set_state(Path::new(line), JobState::Sent)?;
+            }
+            log::info!("imported {} entries from {:?}", store.jobs.len(), legacy);
+        }
+
+        Ok(store)
+    }
+
+    pub fn open_at<P: AsRef<Path>>(path: P) -> io::Result<JobStore> {
+        let path = path.as_ref().to_path_buf();
+        let mut jobs = HashMap::new();
+
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        let exclusive = lock.try_lock().is_ok();
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                // a torn or foreign line is skipped, the previous state for that path stays
+                if let Some((file, job)) = Job::parse(&line) {
+                    jobs.insert(file, job);
+                }
+            }
+        }
+
+        // rewriting the journal under someone else's open handle would lose their writes
+        if exclusive {
+            compact(&path, &jobs)?;
+            lock.unlock()?;
+        }
+        lock.lock_shared()?;
+
+        let journal = OpenOptions::new().append(true).create(true).open(&path)?;
+
+        Ok(JobStore {
+            path,
+            journal: Some(journal),
+            jobs,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Keeps what the store knows but forgets changes instead of writing them down. Used by
+    /// dry runs, so that pretending to send a file doesn't mark it as sent.
+    pub fn into_scratch(self) -> JobStore {
+        JobStore {
+            journal: None,
+            _lock: None,
+            ..self
+        }
+    }
+
+    pub fn state<P: AsRef<Path>>(&self, file: P) -> Option<JobState> {
+        self.jobs.get(file.as_ref()).map(|j| j.state)
+    }
+
+    /// Records a newly discovered file. Returns false if the file is already known and shouldn't
+    /// be processed again (it is in flight, or was sent and hasn't changed since). Failed files
+    /// may be enqueued again.
+    pub fn enqueue<P: AsRef<Path>>(&mut self, file: P) -> io::Result<bool> {
+        let requeue = match self.jobs.get(file.as_ref()) {
+            None => true,
+            Some(job) => match job.state {
+                JobState::Failed => true,
+                // overwritten in place since we sent it. Entries imported from the old log have
+                // no fingerprint, those are left alone
+                JobState::Sent => job
+                    .fingerprint
+                    .is_some_and(|fp| Fingerprint::of(&file).is_some_and(|now| now != fp)),
+                _ => false,
+            },
+        };
+
+        if requeue {
+            self.set_state(file, JobState::Queued)?;
+        }
+        Ok(requeue)
+    }
+
+    /// Records a state change. Entering `Queued` or `Converting` takes a fresh fingerprint of the
+    /// file, as that is the content about to be sent; later states keep it.
+    pub fn set_state<P: AsRef<Path>>(&mut self, file: P, state: JobState) -> io::Result<()> {
+        let file = file.as_ref();
+        let fingerprint = match state {
+            JobState::Queued | JobState::Converting => Fingerprint::of(file),
+            _ => self.jobs.get(file).and_then(|j| j.fingerprint),
+        };
+        let job = Job { state, fingerprint };
+
+        let line = match file.to_str() {
+            Some(f) if !f.contains('\n') => job.line(f),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't record path {:?}", file),
+                ))
+            }
+        };
+
+        if let Some(journal) = &mut self.journal {
+            journal.write_all(line.as_bytes())?;
+            journal.sync_data()?;
+        }
+        self.jobs.insert(file.to_path_buf(), job);
+
+        Ok(())
+    }
+
+    /// Jobs that were queued or mid-flight when kiyomi last stopped, in `scan::path_order`
+    pub fn unfinished(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.state.is_unfinished())
+            .map(|(file, _)| file.clone())
+            .collect();
+        files.sort_by(|a, b| scan::path_order(a, b));
+        files
+    }
+
+    /// Every job and its state, in path order
+    pub fn jobs(&self) -> Vec<(&Path, JobState)> {
+        let mut jobs: Vec<_> = self.jobs.iter().map(|(p, j)| (p.as_path(), j.state)).collect();
+        jobs.sort_by(|a, b| a.0.cmp(b.0));
+        jobs
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Rewrites the journal with one line per job. Written to a temporary file and renamed over the
+/// old journal, so the previous version survives if we crash halfway.
+fn compact(path: &Path, jobs: &HashMap<PathBuf, Job>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        let mut entries: Vec<_> = jobs.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        for (file_path, job) in entries {
+            if let Some(f) = file_path.to_str() {
+                file.write_all(job.line(f).as_bytes())?;
+            }
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn journal_replays_last_state() {
+    let dir = crate::workdir::TempDir::new("replay");
+    let path = dir.join("jobs");
+    {
+        let mut store = JobStore::open_at(&path).unwrap();
+        assert!(store.enqueue("/manga/a/1.cbz").unwrap());
+        assert!(store.enqueue("/manga/a/2.cbz").unwrap());
+        store.set_state("/manga/a/1.cbz", JobState::Sent).unwrap();
+        store.set_state("/manga/a/2.cbz", JobState::Sending).unwrap();
+    }
+
+    let mut store = JobStore::open_at(&path).unwrap();
+    assert_eq!(store.state("/manga/a/1.cbz"), Some(JobState::Sent));
+    assert_eq!(store.unfinished(), vec![PathBuf::from("/manga/a/2.cbz")]);
+    assert!(!store.enqueue("/manga/a/1.cbz").unwrap());
+}
+
+#[test]
+fn journal_ignores_torn_lines() {
+    let dir = crate::workdir::TempDir::new("torn");
+    let path = dir.join("jobs");
+    std::fs::write(&path, "sent\t/manga/a/1.cbz\nsendi").unwrap();
+
+    let mut store = JobStore::open_at(&path).unwrap();
+    assert_eq!(store.state("/manga/a/1.cbz"), Some(JobState::Sent));
+    store.set_state("/manga/a/1.cbz", JobState::Failed).unwrap();
+    assert!(store.enqueue("/manga/a/1.cbz").unwrap());
+}
+
+#[test]
+fn journal_requeues_overwritten_files() {
+    let dir = crate::workdir::TempDir::new("overwrite");
+    let (path, cbz) = (dir.join("jobs"), dir.join("chapter.cbz"));
+    std::fs::write(&cbz, b"first").unwrap();
+
+    let mut store = JobStore::open_at(&path).unwrap();
+    assert!(store.enqueue(&cbz).unwrap());
+    store.set_state(&cbz, JobState::Sent).unwrap();
+    assert!(!store.enqueue(&cbz).unwrap());
+
+    std::fs::write(&cbz, b"second, longer").unwrap();
+    assert!(store.enqueue(&cbz).unwrap());
+
+    // the old two-column format still reads
+    std::fs::write(&path, "sent\t/manga/a/1.cbz\n").unwrap();
+    let store = JobStore::open_at(&path).unwrap();
+    assert_eq!(store.state("/manga/a/1.cbz"), Some(JobState::Sent));
+}
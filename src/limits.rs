@@ -0,0 +1,213 @@
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::config::LimitsConfig;
+
+/// longest we sleep before looking again, so the end of quiet hours or room made by a send that
+/// failed isn't missed by much. The limits themselves are the ones the job started with
+const RECHECK: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Holds emails back so we stay under `[limits]`. Shared by every worker: whoever wants to send
+/// waits until the limits allow it, which keeps the queue behind it waiting too.
+///
+/// Only emails sent since kiyomi started are counted.
+#[derive(Default)]
+pub struct Limiter {
+    /// when each email went out and how large it was, oldest first. Nothing older than a day
+    sent: Mutex<VecDeque<(NaiveDateTime, u64)>>,
+}
+
+/// A place for one email within the limits, taken by `Limiter::wait`. Other senders see it as
+/// sent while it is being sent, and it is given back unless `keep` is called once it went out.
+pub struct Slot<'a> {
+    limiter: &'a Limiter,
+    entry: (NaiveDateTime, u64),
+    kept: bool,
+}
+
+impl Slot<'_> {
+    /// The email went out, count it
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut sent = self.limiter.sent.lock().unwrap();
+        if let Some(i) = sent.iter().rposition(|e| *e == self.entry) {
+            sent.remove(i);
+        }
+    }
+}
+
+impl Limiter {
+    /// Blocks until an email of `bytes` may be sent and holds a place for it
+    pub fn wait(&self, bytes: u64, limits: &LimitsConfig) -> Slot<'_> {
+        let mut announced = None;
+        loop {
+            let now = chrono::Local::now().naive_local();
+            let mut sent = self.sent.lock().unwrap();
+            while sent.front().is_some_and(|(t, _)| now - *t >= Duration::days(1)) {
+                sent.pop_front();
+            }
+
+            let until = match next_slot(&sent, bytes, limits, now) {
+                None => {
+                    sent.push_back((now, bytes));
+                    return Slot {
+                        limiter: self,
+                        entry: (now, bytes),
+                        kept: false,
+                    };
+                }
+                Some(until) => until,
+            };
+            drop(sent);
+
+            if announced != Some(until) {
+                log::info!("holding the email back until {} to stay within [limits]", until.format("%Y-%m-%d %H:%M:%S"));
+                announced = Some(until);
+            }
+            let pause = (until - now).to_std().unwrap_or_default().min(RECHECK);
+            std::thread::sleep(pause.max(std::time::Duration::from_millis(100)));
+        }
+    }
+}
+
+/// When an email of `bytes` may go out, if not right away
+fn next_slot(
+    sent: &VecDeque<(NaiveDateTime, u64)>,
+    bytes: u64,
+    limits: &LimitsConfig,
+    now: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let mut until = now;
+
+    for (limit, window) in [
+        (limits.per_minute, Duration::minutes(1)),
+        (limits.per_hour, Duration::hours(1)),
+    ] {
+        let Some(limit) = limit else { continue };
+        let recent: Vec<_> = sent.iter().filter(|(t, _)| now - *t < window).collect();
+        if recent.len() >= limit as usize {
+            // once enough of them have left the window there's room again
+            let (t, _) = recent[recent.len() - limit as usize];
+            until = until.max(*t + window);
+        }
+    }
+
+    if let Some(mb) = limits.mb_per_day {
+        let limit = mb * 1024 * 1024;
+        let mut total: u64 = sent.iter().map(|(_, b)| b).sum();
+        // something larger than the whole allowance still goes out, on a day of its own
+        for (t, b) in sent {
+            if total + bytes <= limit || total == 0 {
+                break;
+            }
+            total -= b;
+            until = until.max(*t + Duration::days(1));
+        }
+    }
+
+    // a window may end inside another one
+    while let Some(end) = quiet_until(until, &limits.quiet_hours) {
+        until = end;
+    }
+
+    (until > now).then_some(until)
+}
+
+/// `"22:00-07:00"`, from and to in local time. Windows that end before they start go over midnight
+pub fn parse_quiet_hours(window: &str) -> Result<(NaiveTime, NaiveTime), String> {
+    let parse = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M")
+            .map_err(|_| format!("limits.quiet_hours: expected \"HH:MM-HH:MM\", got {:?}", window))
+    };
+    let (from, to) = window
+        .split_once('-')
+        .ok_or_else(|| format!("limits.quiet_hours: expected \"HH:MM-HH:MM\", got {:?}", window))?;
+    Ok((parse(from)?, parse(to)?))
+}
+
+/// The end of the quiet window `time` is in, if any
+fn quiet_until(time: NaiveDateTime, windows: &[String]) -> Option<NaiveDateTime> {
+    let clock = time.time();
+    let date = time.date();
+
+    windows.iter().filter_map(|w| parse_quiet_hours(w).ok()).find_map(|(from, to)| {
+        if from <= to {
+            (from <= clock && clock < to).then(|| date.and_time(to))
+        } else if clock >= from {
+            Some(date.succ_opt()?.and_time(to))
+        } else {
+            (clock < to).then(|| date.and_time(to))
+        }
+    })
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn rate_limits_and_quiet_hours() {
+    let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+    let limits = LimitsConfig {
+        per_minute: Some(2),
+        per_hour: Some(3),
+        mb_per_day: Some(10),
+        quiet_hours: vec!["23:00-07:00".into()],
+    };
+    let mb = 1024 * 1024;
+
+    let mut sent = VecDeque::new();
+    assert_eq!(next_slot(&sent, mb, &limits, at("2025-01-01 12:00:00")), None);
+
+    sent.push_back((at("2025-01-01 12:00:00"), mb));
+    sent.push_back((at("2025-01-01 12:00:20"), mb));
+    // two in the last minute, the third waits for the first to leave the window
+    assert_eq!(
+        next_slot(&sent, mb, &limits, at("2025-01-01 12:00:30")),
+        Some(at("2025-01-01 12:01:00"))
+    );
+
+    sent.push_back((at("2025-01-01 12:05:00"), mb));
+    // three in the last hour
+    assert_eq!(
+        next_slot(&sent, mb, &limits, at("2025-01-01 12:10:00")),
+        Some(at("2025-01-01 13:00:00"))
+    );
+
+    // 3 MB sent, 8 more would go over the 10 MB a day
+    assert_eq!(
+        next_slot(&sent, 8 * mb, &limits, at("2025-01-01 14:00:00")),
+        Some(at("2025-01-02 12:00:00"))
+    );
+
+    // quiet from 23:00 until 7:00 the next morning
+    assert_eq!(
+        next_slot(&sent, mb, &limits, at("2025-01-01 23:30:00")),
+        Some(at("2025-01-02 07:00:00"))
+    );
+    assert_eq!(
+        next_slot(&VecDeque::new(), mb, &limits, at("2025-01-02 06:59:00")),
+        Some(at("2025-01-02 07:00:00"))
+    );
+}
+
+#[test]
+fn only_sent_emails_count() {
+    let limiter = Limiter::default();
+    let limits = LimitsConfig::default();
+
+    // failed, so given back
+    drop(limiter.wait(10, &limits));
+    assert!(limiter.sent.lock().unwrap().is_empty());
+
+    limiter.wait(10, &limits).keep();
+    assert_eq!(limiter.sent.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,283 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use crate::config::{LogConfig, LogFormat};
+
+/// What the current thread is working on. Attached to every line it logs
+#[derive(Debug, Clone, Default)]
+struct JobContext {
+    file: Option<String>,
+    series: Option<String>,
+    part: Option<(usize, usize)>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<JobContext> = RefCell::new(JobContext::default());
+}
+
+/// Tags everything logged on this thread with the job's file and series until dropped
+pub struct JobScope {
+    _private: (),
+}
+
+pub fn job_scope(file: &Path) -> JobScope {
+    CONTEXT.with(|c| {
+        *c.borrow_mut() = JobContext {
+            file: file.file_name().map(|f| f.to_string_lossy().into_owned()),
+            series: file
+                .parent()
+                .and_then(|p| p.file_name())
+                .map(|s| s.to_string_lossy().into_owned()),
+            part: None,
+        }
+    });
+    JobScope { _private: () }
+}
+
+/// Adds the part to the current job's context until dropped. `index` is zero-based, like
+/// `pipeline::Part::index`
+pub struct PartScope {
+    _private: (),
+}
+
+pub fn part_scope(index: usize, total: usize) -> PartScope {
+    CONTEXT.with(|c| {
+        let mut context = c.borrow_mut();
+        if context.file.is_some() {
+            context.part = Some((index + 1, total));
+        }
+    });
+    PartScope { _private: () }
+}
+
+impl Drop for PartScope {
+    fn drop(&mut self) {
+        CONTEXT.with(|c| c.borrow_mut().part = None);
+    }
+}
+
+impl Drop for JobScope {
+    fn drop(&mut self) {
+        CONTEXT.with(|c| *c.borrow_mut() = JobContext::default());
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> std::io::Result<LogFile> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// kiyomi.log -> kiyomi.log.1 -> kiyomi.log.2 ..., the oldest falls off the end
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.keep == 0 {
+            let _ = std::fs::remove_file(&self.path);
+        } else {
+            let _ = std::fs::remove_file(numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(numbered(n), numbered(n + 1));
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+struct Settings {
+    format: LogFormat,
+    file: Option<(PathBuf, Mutex<LogFile>)>,
+}
+
+struct Logger {
+    settings: RwLock<Settings>,
+}
+
+static LOGGER: Logger = Logger {
+    settings: RwLock::new(Settings {
+        format: LogFormat::Text,
+        file: None,
+    }),
+};
+
+/// Installs the logger with defaults, so that anything going wrong while reading the config
+/// can be reported. `configure` applies the real settings once the config is loaded.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Applies `[log]`. Safe to call again when the config is reloaded
+pub fn configure(config: &LogConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let level = config.level_filter()?;
+
+    let mut settings = LOGGER.settings.write().unwrap();
+
+    match &config.file {
+        // already writing there, keep the handle but pick up the new limits
+        Some(path) if settings.file.as_ref().is_some_and(|(p, _)| p == path) => {
+            if let Some((_, file)) = &settings.file {
+                let mut file = file.lock().unwrap();
+                file.max_size = config.max_size * 1024 * 1024;
+                file.keep = config.keep;
+            }
+        }
+        Some(path) => {
+            let file = LogFile::open(path, config.max_size * 1024 * 1024, config.keep)
+                .map_err(|e| format!("couldn't open log file {:?}: {}", path, e))?;
+            settings.file = Some((path.clone(), Mutex::new(file)));
+        }
+        None => settings.file = None,
+    }
+
+    settings.format = config.format;
+    log::set_max_level(level);
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let settings = self.settings.read().unwrap();
+        let context = CONTEXT.with(|c| c.borrow().clone());
+        let line = match settings.format {
+            LogFormat::Text => text_line(record, &context),
+            LogFormat::Json => json_line(record, &context),
+        };
+
+        let _ = std::io::stderr().write_all(line.as_bytes());
+        if let Some((_, file)) = &settings.file {
+            let _ = file.lock().unwrap().write(&line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+        if let Some((_, file)) = &self.settings.read().unwrap().file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+fn timestamp() -> String {
+    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+}
+
+fn text_line(record: &Record, context: &JobContext) -> String {
+    let mut tags = Vec::new();
+    // epub-builder and whatever else logs through us
+    if !record.target().starts_with("kiyomi") {
+        tags.push(record.target().to_string());
+    }
+    if let (Some(series), Some(file)) = (&context.series, &context.file) {
+        tags.push(format!("{}/{}", series, file));
+    }
+    if let Some((part, total)) = context.part {
+        tags.push(format!("part {}/{}", part, total));
+    }
+    let tags = if tags.is_empty() {
+        String::new()
+    } else {
+        format!("[{}] ", tags.join(" "))
+    };
+
+    let level = match record.level() {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    };
+
+    format!("{} {} {}{}\n", timestamp(), level, tags, record.args())
+}
+
+fn json_line(record: &Record, context: &JobContext) -> String {
+    let mut line = serde_json::json!({
+        "time": timestamp(),
+        "level": record.level().as_str().to_lowercase(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(file) = &context.file {
+        line["file"] = file.clone().into();
+    }
+    if let Some(series) = &context.series {
+        line["series"] = series.clone().into();
+    }
+    if let Some((part, total)) = context.part {
+        line["part"] = part.into();
+        line["parts"] = total.into();
+    }
+    format!("{}\n", line)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn log_file_rotates() {
+    let dir = crate::workdir::TempDir::new("log");
+    let path = dir.join("kiyomi.log");
+
+    let mut file = LogFile::open(&path, 10, 2).unwrap();
+    for line in ["one 1234\n", "two 1234\n", "three 12\n", "four 123\n"] {
+        file.write(line).unwrap();
+    }
+
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("kiyomi.log"), "four 123\n");
+    assert_eq!(read("kiyomi.log.1"), "three 12\n");
+    assert_eq!(read("kiyomi.log.2"), "two 1234\n");
+    assert!(!dir.join("kiyomi.log.3").exists());
+}
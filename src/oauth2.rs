@@ -0,0 +1,282 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::OAuth2Config;
+
+/// Access tokens we already have, by token endpoint and client. Shared by all jobs so a split
+/// chapter or a queue of them doesn't ask for a new token for every email.
+static TOKENS: Mutex<Option<HashMap<(String, String), AccessToken>>> = Mutex::new(None);
+
+/// refresh a little before the provider says the token expires
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+struct AccessToken {
+    token: String,
+    expires: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// seconds
+    expires_in: Option<u64>,
+    /// some providers hand out a new refresh token every time
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// The token endpoint answered, but not with a token
+#[derive(Debug)]
+pub struct EndpointError {
+    pub status: ureq::http::StatusCode,
+    reason: String,
+}
+
+impl std::fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the token endpoint answered {}: {}", self.status, self.reason)
+    }
+}
+
+impl Error for EndpointError {}
+
+fn key(config: &OAuth2Config) -> (String, String) {
+    (config.token_url.clone(), config.client_id.clone())
+}
+
+/// A valid access token, from the cache or freshly fetched with the refresh token
+pub fn access_token(config: &OAuth2Config, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let mut tokens = TOKENS.lock().unwrap();
+    let tokens = tokens.get_or_insert_with(HashMap::new);
+
+    if let Some(cached) = tokens.get(&key(config)) {
+        if cached.expires > Instant::now() {
+            return Ok(cached.token.clone());
+        }
+    }
+
+    let token = fetch(config, timeout)?;
+    tokens.insert(key(config), token.clone());
+    Ok(token.token)
+}
+
+/// Drops the cached access token, e.g. because the server didn't accept it
+pub fn forget(config: &OAuth2Config) {
+    if let Some(tokens) = TOKENS.lock().unwrap().as_mut() {
+        tokens.remove(&key(config));
+    }
+}
+
+fn fetch(config: &OAuth2Config, timeout: Duration) -> Result<AccessToken, Box<dyn Error>> {
+    let token_file = token_file(config)?;
+    let refresh_token = refresh_token(config, &token_file)?;
+
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .tls_config(
+            ureq::tls::TlsConfig::builder()
+                .provider(ureq::tls::TlsProvider::NativeTls)
+                .build(),
+        )
+        .http_status_as_error(false)
+        .timeout_global(Some(timeout))
+        .build()
+        .into();
+
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_str()),
+        ("client_id", config.client_id.as_str()),
+    ];
+    if !config.client_secret.is_empty() {
+        form.push(("client_secret", config.client_secret.as_str()));
+    }
+    if let Some(scope) = &config.scope {
+        form.push(("scope", scope.as_str()));
+    }
+
+    log::debug!("fetching an access token from {}", config.token_url);
+    // network trouble is returned as is, so it can be retried
+    let mut response = agent.post(&config.token_url).send_form(form)?;
+    let status = response.status();
+    let body = response.body_mut().read_to_string()?;
+
+    if !status.is_success() {
+        let reason = match serde_json::from_str::<TokenError>(&body) {
+            Ok(TokenError { error, error_description: Some(d) }) => format!("{}: {}", error, d),
+            Ok(TokenError { error, .. }) => error,
+            Err(_) => body.chars().take(200).collect(),
+        };
+        return Err(EndpointError { status, reason }.into());
+    }
+
+    let response: TokenResponse =
+        serde_json::from_str(&body).map_err(|e| format!("unexpected answer from the token endpoint: {}", e))?;
+
+    if let Some(new) = response.refresh_token.filter(|t| *t != refresh_token) {
+        store_refresh_token(&token_file, &new)
+            .map_err(|e| format!("couldn't store the new refresh token in {:?}: {}", token_file, e))?;
+        log::info!("stored a new refresh token in {:?}", token_file);
+    }
+
+    let lifetime = Duration::from_secs(response.expires_in.unwrap_or(3600));
+    Ok(AccessToken {
+        token: response.access_token,
+        expires: Instant::now() + lifetime.saturating_sub(EXPIRY_MARGIN),
+    })
+}
+
+/// `smtp.oauth2.token_file`, `~/.local/share/kiyomi/oauth2-refresh-token` if not set
+fn token_file(config: &OAuth2Config) -> Result<PathBuf, Box<dyn Error>> {
+    match &config.token_file {
+        Some(file) => Ok(file.clone()),
+        None => Ok(dirs::data_dir()
+            .ok_or("the data directory could not be found")?
+            .join("kiyomi/oauth2-refresh-token")),
+    }
+}
+
+/// The newest refresh token we know of: the stored one, or the one from the config
+fn refresh_token(config: &OAuth2Config, token_file: &Path) -> Result<String, Box<dyn Error>> {
+    match std::fs::read_to_string(token_file) {
+        Ok(token) if !token.trim().is_empty() => return Ok(token.trim().to_string()),
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(format!("couldn't read {:?}: {}", token_file, e).into()),
+    }
+
+    if config.refresh_token.is_empty() {
+        return Err("no refresh token, set smtp.oauth2.refresh_token".into());
+    }
+    Ok(config.refresh_token.clone())
+}
+
+fn store_refresh_token(path: &Path, token: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    std::io::Write::write_all(&mut options.open(&tmp)?, token.as_bytes())?;
+    std::fs::rename(&tmp, path)
+}
+
+/// A token endpoint on localhost that answers `answers` requests with `status` and `body` in
+/// turn. Returns its url and the request bodies it got.
+#[cfg(test)]
+pub fn mock_token_endpoint(answers: Vec<(u16, &'static str)>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/token", listener.local_addr().unwrap());
+
+    let server = std::thread::spawn(move || {
+        let mut requests = Vec::new();
+        for (status, body) in answers {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut request = vec![0; length];
+            reader.read_exact(&mut request).unwrap();
+            requests.push(String::from_utf8(request).unwrap());
+
+            let response = format!(
+                "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+        requests
+    });
+
+    (url, server)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn refresh_token_is_rotated_and_access_token_cached() {
+    let dir = crate::workdir::TempDir::new("oauth2");
+
+    let (token_url, server) = mock_token_endpoint(vec![
+        (200, r#"{"access_token":"at-1","expires_in":3600,"refresh_token":"rt-2"}"#),
+        (400, r#"{"error":"invalid_grant","error_description":"Token has been revoked."}"#),
+    ]);
+    let config = OAuth2Config {
+        token_url,
+        client_id: "kiyomi".into(),
+        client_secret: "secret".into(),
+        refresh_token: "rt-1".into(),
+        token_file: Some(dir.join("token")),
+        ..Default::default()
+    };
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(access_token(&config, timeout).unwrap(), "at-1");
+    // cached, the endpoint isn't asked again
+    assert_eq!(access_token(&config, timeout).unwrap(), "at-1");
+    assert_eq!(std::fs::read_to_string(dir.join("token")).unwrap(), "rt-2");
+
+    forget(&config);
+    let error = access_token(&config, timeout).unwrap_err().to_string();
+    assert!(error.contains("invalid_grant: Token has been revoked."), "{}", error);
+
+    let requests = server.join().unwrap();
+    assert!(requests[0].contains("grant_type=refresh_token") && requests[0].contains("refresh_token=rt-1"));
+    // the stored token wins over the one in the config
+    assert!(requests[1].contains("refresh_token=rt-2"));
+}
+
+#[test]
+fn endpoint_trouble_is_retried() {
+    let dir = crate::workdir::TempDir::new("oauth2-retry");
+    let (token_url, server) = mock_token_endpoint(vec![
+        (503, "Service Unavailable"),
+        (400, r#"{"error":"invalid_grant"}"#),
+    ]);
+    let config = OAuth2Config {
+        token_url,
+        client_id: "kiyomi".into(),
+        refresh_token: "rt-1".into(),
+        token_file: Some(dir.join("token")),
+        ..Default::default()
+    };
+
+    let timeout = Duration::from_secs(5);
+    assert!(crate::retry::is_transient(access_token(&config, timeout).unwrap_err().as_ref()));
+    assert!(!crate::retry::is_transient(access_token(&config, timeout).unwrap_err().as_ref()));
+    server.join().unwrap();
+}
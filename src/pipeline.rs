@@ -0,0 +1,294 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::KiyomiConfig, convert, email::SharedMailer, limits::Limiter, logging, retry, template};
+
+/// What we know about a chapter, for `[templates]`
+#[derive(Debug, Clone, Default)]
+pub struct Chapter {
+    pub series: String,
+    pub title: String,
+    /// as written in the ComicInfo.xml or the file name, empty if neither has one
+    pub number: String,
+}
+
+impl Chapter {
+    /// From the ComicInfo.xml where it says, from the file and directory names otherwise
+    fn of(cbz: &Path, comic_info: Option<&convert::ComicInfo>, fallback_series: &str) -> Chapter {
+        let stem = cbz.file_stem().unwrap_or_default().to_string_lossy();
+        Chapter {
+            series: comic_info
+                .and_then(|ci| ci.series.clone())
+                .unwrap_or_else(|| fallback_series.to_string()),
+            title: comic_info.and_then(|ci| ci.title.clone()).unwrap_or_else(|| stem.to_string()),
+            number: comic_info
+                .and_then(|ci| ci.number.clone())
+                .or_else(|| number_in(&stem))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// The last number in a file name like "Vol.2 Ch.10.5", the chapter in most naming schemes
+fn number_in(name: &str) -> Option<String> {
+    let mut numbers = Vec::new();
+    let mut current = String::new();
+    for c in name.chars().chain([' ']) {
+        if c.is_ascii_digit() || (c == '.' && !current.is_empty()) {
+            current.push(c);
+        } else if !current.is_empty() {
+            numbers.push(current.trim_end_matches('.').to_string());
+            current.clear();
+        }
+    }
+    numbers.pop()
+}
+
+/// One epub built from a chapter. Chapters too large for one email are split into several parts
+pub struct Part {
+    pub path: PathBuf,
+    /// zero-based
+    pub index: usize,
+    pub total: usize,
+    pub pages: usize,
+    pub chapter: Chapter,
+}
+
+impl Part {
+    /// The placeholders of `[templates]`. `{size}` is left out while the epub doesn't exist yet
+    fn values(&self, kiyomi_config: &KiyomiConfig) -> Vec<(&'static str, String)> {
+        let mut values = vec![
+            ("series", self.chapter.series.clone()),
+            ("title", self.chapter.title.clone()),
+            ("chapter", self.chapter.number.clone()),
+            ("part", (self.index + 1).to_string()),
+            ("parts", self.total.to_string()),
+            ("pages", self.pages.to_string()),
+            ("subject", kiyomi_config.smtp.subject.clone()),
+        ];
+        if let Ok(m) = std::fs::metadata(&self.path) {
+            values.push(("size", format!("{:.1} MB", m.len() as f64 / (1024.0 * 1024.0))));
+        }
+        values
+    }
+}
+
+/// Extracts a .cbz and builds its epub(s) in `out_dir`, split so that each stays under
+/// `options.size_limit`.
+pub fn convert(cbz: &Path, kiyomi_config: &KiyomiConfig, out_dir: &Path) -> Result<Vec<Part>, Box<dyn Error>> {
+    // some sources don't provide nicely tagged files. In this case, we at least want the manga title
+    let fallback_title = match cbz.parent() {
+        Some(p) => {
+            let dir_name = match p.file_name() {
+                Some(name) => name,
+                None => return Err("couldn't get directory name".into()),
+            };
+            dir_name.to_str().unwrap_or("Unknown manga")
+        }
+        None => return Err("couldn't get parent directory".into()),
+    };
+    let output_path = out_dir.to_str().ok_or("output directory path isn't valid utf-8")?;
+
+    log::info!("reading cbz file: {:?}", cbz);
+
+    let (pages, comic_info) = convert::extract_images_from_cbz(cbz)?;
+
+    // [series."<name>"] goes by the directory or the ComicInfo.xml
+    let mut series = vec![fallback_title];
+    series.extend(comic_info.as_ref().and_then(|ci| ci.series.as_deref()));
+    let image_config = kiyomi_config.image_for(&series);
+    if let Some(profile) = image_config.profile()? {
+        log::debug!("fitting the pages to {}x{}", profile.width, profile.height);
+    }
+    // a page that can't be converted fails the chapter, rather than sending it with a gap
+    let mut pages: Vec<_> = pages
+        .into_iter()
+        .map(|page| convert::prepare_page(page, &image_config))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    convert::unique_names(&mut pages);
+    let manga = (pages, comic_info);
+
+    // kiyomi sends email, which has a size limit. We need to stay below 20MB by splitting the manga
+    // and bulding multiple epubs
+
+    let cover_image = manga.0.first();
+
+    // let user choose size to slip over
+    // 25MB is the default size for email attachments
+    let size_limit = kiyomi_config.options.size_limit as usize * 1024 * 1024;
+    log::debug!("using size limit of {}MB", kiyomi_config.options.size_limit);
+
+    let mut current_size = 0;
+    let mut files = Vec::new();
+    let mut current_epub = Vec::new();
+    for image in manga.0.iter() {
+        current_size += image.contents.len();
+        if current_size > size_limit {
+            files.push(current_epub);
+            current_epub = Vec::new();
+            current_size = image.contents.len();
+        }
+        current_epub.push(image);
+    }
+    if !current_epub.is_empty() {
+        files.push(current_epub);
+    }
+    if files.len() > 1 {
+        log::info!("manga will be split into {} parts due to size constraints", files.len());
+    }
+
+    let chapter = Chapter::of(cbz, manga.1.as_ref(), fallback_title);
+
+    let mut parts = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let _part = logging::part_scope(i, files.len());
+        log::debug!("building part {} of {}", i + 1, files.len());
+        let mut part = Part {
+            path: PathBuf::new(),
+            index: i,
+            total: files.len(),
+            pages: file.len(),
+            chapter: chapter.clone(),
+        };
+        let file_name = file_name(&part, kiyomi_config, &parts);
+
+        // now we have a vector of epubs. Let's build them
+        let path = convert::build_epub_from_images(
+            (file, manga.1.clone()),
+            cover_image,
+            &format!("{} - part {}", fallback_title, i + 1),
+            output_path,
+            if files.len() > 1 { Some((i, files.len())) } else { None },
+            file_name.as_deref(),
+            &image_config,
+        )
+        .map_err(|e| format!("epub error: {}", e))?;
+
+        part.path = PathBuf::from(path);
+        parts.push(part);
+    }
+
+    Ok(parts)
+}
+
+/// `templates.filename` filled in, made safe to use as a file name. Parts the template doesn't
+/// tell apart get their number added
+fn file_name(part: &Part, kiyomi_config: &KiyomiConfig, built: &[Part]) -> Option<String> {
+    let template = kiyomi_config.templates.filename.as_ref()?;
+    let name: String = template::render(template, &part.values(kiyomi_config))
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\') || c.is_control() { '_' } else { c })
+        .collect();
+    let name = name.trim();
+
+    let taken = built.iter().any(|p| p.path.file_stem().is_some_and(|s| s.to_string_lossy() == name));
+    if name.is_empty() || taken {
+        return Some(format!("{} ({})", name, part.index + 1).trim().to_string());
+    }
+    Some(name.to_string())
+}
+
+/// The subject a part is sent with, `templates.subject` filled in
+pub fn subject(part: &Part, kiyomi_config: &KiyomiConfig) -> String {
+    template::render(&kiyomi_config.templates.subject, &part.values(kiyomi_config))
+}
+
+/// The text of the email a part is sent with, `templates.body` filled in
+pub fn body(part: &Part, kiyomi_config: &KiyomiConfig) -> String {
+    template::render(&kiyomi_config.templates.body, &part.values(kiyomi_config))
+}
+
+/// `templates.subject` for an epub we didn't build ourselves
+pub fn file_subject(epub: &Path, kiyomi_config: &KiyomiConfig) -> String {
+    subject(&file_part(epub), kiyomi_config)
+}
+
+/// `templates.body` for an epub we didn't build ourselves
+pub fn file_body(epub: &Path, kiyomi_config: &KiyomiConfig) -> String {
+    body(&file_part(epub), kiyomi_config)
+}
+
+/// All we know of an epub we didn't build ourselves is its name and size
+fn file_part(epub: &Path) -> Part {
+    Part {
+        path: epub.to_path_buf(),
+        index: 0,
+        total: 1,
+        pages: 0,
+        chapter: Chapter {
+            title: epub.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            ..Default::default()
+        },
+    }
+}
+
+/// Emails one part to the kindle, retrying transient failures as `[retry]` says
+pub fn send(part: &Part, kiyomi_config: &KiyomiConfig, mailer: &SharedMailer, limiter: &Limiter) -> Result<(), retry::Failure> {
+    let subject = subject(part, kiyomi_config);
+    let body = body(part, kiyomi_config);
+    retry::retry(&kiyomi_config.retry, || send_file(&part.path, &subject, &body, kiyomi_config, mailer, limiter))
+}
+
+/// Emails an epub as it is. In a dry run, only says what would be sent
+pub fn send_file(
+    epub: &Path,
+    subject: &str,
+    body: &str,
+    kiyomi_config: &KiyomiConfig,
+    mailer: &SharedMailer,
+    limiter: &Limiter,
+) -> Result<(), Box<dyn Error>> {
+    send_files(&[epub], subject, body, kiyomi_config, mailer, limiter)
+}
+
+/// Emails several epubs in one go, once `[limits]` allow it. Every attempt waits its turn, and
+/// only an email that went out counts against the limits
+pub fn send_files(
+    epubs: &[&Path],
+    subject: &str,
+    body: &str,
+    kiyomi_config: &KiyomiConfig,
+    mailer: &SharedMailer,
+    limiter: &Limiter,
+) -> Result<(), Box<dyn Error>> {
+    let smtp = &kiyomi_config.smtp;
+
+    let mut size = 0;
+    for epub in epubs {
+        size += std::fs::metadata(epub)?.len();
+    }
+
+    if kiyomi_config.options.dry_run {
+        let names: Vec<_> = epubs.iter().map(|e| e.file_name().unwrap_or_default()).collect();
+        log::info!(
+            "[dry run] would email {:?} to {} with {:?} attached ({:.1} MB)",
+            subject,
+            smtp.to_email,
+            names,
+            size as f64 / (1024.0 * 1024.0)
+        );
+        return Ok(());
+    }
+    // a big batch of downloads waits here rather than getting the account throttled
+    let slot = limiter.wait(size, &kiyomi_config.limits);
+    mailer.send(smtp, subject, body, epubs)?;
+    slot.keep();
+    Ok(())
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn chapter_numbers_from_file_names() {
+    assert_eq!(number_in("Vol.2 Ch.10.5").as_deref(), Some("10.5"));
+    assert_eq!(number_in("ch3").as_deref(), Some("3"));
+    assert_eq!(number_in("Chapter 12.").as_deref(), Some("12"));
+    assert_eq!(number_in("Oneshot"), None);
+}
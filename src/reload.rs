@@ -0,0 +1,106 @@
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    sync::{mpsc, Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    config::{self, ConfigSource, KiyomiConfig},
+    logging,
+};
+
+/// The config every new job starts with. Swapped as a whole when kiyomi.toml changes, a job
+/// that is already running keeps the copy it started with.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<KiyomiConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: KiyomiConfig) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn current(&self) -> Arc<KiyomiConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, config: KiyomiConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+/// Reloads the config whenever the file changes. A config that doesn't load or validate is
+/// reported and the previous one stays active. The returned watcher must be kept alive.
+pub fn watch_config(source: ConfigSource, shared: SharedConfig) -> Result<RecommendedWatcher, Box<dyn std::error::Error>> {
+    // events come with absolute paths, a relative --config would never match them
+    let path = std::path::absolute(source.path()?)?;
+    // editors usually write a new file and rename it over the old one, which a watch on the
+    // file itself would lose track of. Watch the directory instead
+    let dir = path.parent().ok_or("the config file has no parent directory")?.to_path_buf();
+
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    std::thread::Builder::new()
+        .name("kiyomi-config".to_string())
+        .spawn(move || {
+            let touches_config = |res: &notify::Result<Event>| match res {
+                Ok(event) => !event.kind.is_access() && event.paths.iter().any(|p| p == &path),
+                Err(_) => false,
+            };
+
+            while let Ok(res) = rx.recv() {
+                if !touches_config(&res) {
+                    continue;
+                }
+                // a save is often several events in a row, let them settle
+                while rx.recv_timeout(Duration::from_millis(500)).is_ok() {}
+
+                reload(&source, &shared);
+            }
+        })?;
+
+    Ok(watcher)
+}
+
+fn reload(source: &ConfigSource, shared: &SharedConfig) {
+    // moved away or deleted, don't let get_config put a default one in its place
+    if source.path().is_ok_and(|p| !p.exists()) {
+        log::warn!("the config file is gone, keeping the previous config");
+        return;
+    }
+
+    let mut new = match config::get_config(source).and_then(|c| config::validate_config(&c).map(|_| c)) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("config error, keeping the previous config: {}", e);
+            return;
+        }
+    };
+
+    let old = shared.current();
+    // these are only read at startup
+    let same = |a: Result<toml::Value, toml::ser::Error>, b: Result<toml::Value, toml::ser::Error>| {
+        matches!((a, b), (Ok(a), Ok(b)) if a == b)
+    };
+    if !same(toml::Value::try_from(&old.watcher), toml::Value::try_from(&new.watcher))
+        || old.directories.manga != new.directories.manga
+        || old.options.workers != new.options.workers
+        || old.batch.enabled != new.batch.enabled
+        || old.options.dry_run != new.options.dry_run
+    {
+        log::warn!(
+            "changes to [watcher], directories.manga, options.workers, options.dry_run and batch.enabled take effect after a restart"
+        );
+    }
+    // the job store was picked for dry_run at startup, sending for real into a scratch store
+    // (or the other way round) would lose track of what was sent
+    new.options.dry_run = old.options.dry_run;
+
+    if let Err(e) = logging::configure(&new.log) {
+        log::error!("config error, keeping the previous config: {}", e);
+        return;
+    }
+    shared.replace(new);
+    log::info!("config reloaded, new jobs will use it");
+}
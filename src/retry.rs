@@ -0,0 +1,109 @@
+use std::{error::Error, time::Duration};
+
+use crate::{config::RetryConfig, oauth2};
+
+/// The last error of something that was tried `attempts` times
+#[derive(Debug)]
+pub struct Failure {
+    pub error: Box<dyn Error>,
+    pub attempts: u32,
+}
+
+/// Whether trying again could help. The server saying no (5xx, bad credentials), a certificate
+/// we don't trust or a message we can't even build won't get better by waiting, a dropped
+/// connection or a 4xx might. So can a token endpoint that is having trouble (HTTP 5xx or 429).
+pub fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    if let Some(e) = error.downcast_ref::<lettre::transport::smtp::Error>() {
+        if e.is_transient() {
+            return true;
+        }
+        // no answer from the server: only worth another go if the network let us down. lettre
+        // also reports a failed TLS handshake (bad certificate, no TLS on that port) as a
+        // connection error, but with the handshake error as its cause
+        return !(e.is_permanent() || e.is_client() || e.is_tls() || e.is_response())
+            && e.source().is_some_and(|cause| cause.is::<std::io::Error>());
+    }
+    if let Some(e) = error.downcast_ref::<oauth2::EndpointError>() {
+        return e.status.is_server_error() || e.status == ureq::http::StatusCode::TOO_MANY_REQUESTS;
+    }
+    // the OAuth2 token endpoint couldn't be reached
+    matches!(
+        error.downcast_ref::<ureq::Error>(),
+        Some(ureq::Error::Io(_) | ureq::Error::Timeout(_) | ureq::Error::HostNotFound | ureq::Error::ConnectionFailed)
+    )
+}
+
+/// How long to wait before each retry: `initial_delay`, doubled every time up to `max_delay`
+pub fn delays(config: &RetryConfig) -> impl Iterator<Item = Duration> {
+    let max = config.max_delay.max(config.initial_delay);
+    std::iter::successors(Some(config.initial_delay), move |d| Some((d * 2).min(max)))
+        .take(config.retries as usize)
+        .map(Duration::from_secs)
+}
+
+/// Runs `f` until it succeeds, fails for good or runs out of retries. Sleeps on the calling
+/// thread in between.
+pub fn retry<F>(config: &RetryConfig, mut f: F) -> Result<(), Failure>
+where
+    F: FnMut() -> Result<(), Box<dyn Error>>,
+{
+    let mut delays = delays(config);
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        let error = match f() {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        if !is_transient(error.as_ref()) {
+            return Err(Failure { error, attempts });
+        }
+        match delays.next() {
+            Some(delay) => {
+                log::warn!("attempt {} failed, retrying in {}s: {}", attempts, delay.as_secs(), error);
+                std::thread::sleep(delay);
+            }
+            None => return Err(Failure { error, attempts }),
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn delays_back_off_up_to_the_max() {
+    let config = RetryConfig {
+        retries: 5,
+        initial_delay: 10,
+        max_delay: 60,
+    };
+    let secs: Vec<u64> = delays(&config).map(|d| d.as_secs()).collect();
+    assert_eq!(secs, [10, 20, 40, 60, 60]);
+
+    let config = RetryConfig {
+        retries: 0,
+        ..config
+    };
+    assert_eq!(delays(&config).count(), 0);
+}
+
+#[test]
+fn permanent_errors_are_not_retried() {
+    let config = RetryConfig {
+        retries: 3,
+        initial_delay: 0,
+        max_delay: 0,
+    };
+    let mut calls = 0;
+    let failure = retry(&config, || {
+        calls += 1;
+        Err("bad address".into())
+    })
+    .unwrap_err();
+    assert_eq!(calls, 1);
+    assert_eq!(failure.attempts, 1);
+}
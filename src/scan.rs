@@ -0,0 +1,119 @@
+use std::{
+    cmp::Ordering,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::jobs::JobStore;
+
+/// Walks the manga directory and returns every .cbz the job store has never seen, in `path_order`
+/// (so chapters of a series come out in order). With `max_age` set, files last modified longer
+/// ago than that are left alone.
+pub fn backfill(root: &Path, job_store: &JobStore, max_age: Option<Duration>) -> io::Result<Vec<PathBuf>> {
+    let cutoff = max_age.and_then(|age| SystemTime::now().checked_sub(age));
+
+    let mut found = Vec::new();
+    walk(root, &mut found)?;
+
+    let mut new_files = Vec::new();
+    for path in found {
+        if job_store.state(&path).is_some() {
+            continue;
+        }
+        if let Some(cutoff) = cutoff {
+            match std::fs::metadata(&path).and_then(|m| m.modified()) {
+                Ok(modified) if modified < cutoff => continue,
+                Ok(_) => (),
+                Err(e) => {
+                    log::warn!("couldn't read {:?}: {}", path, e);
+                    continue;
+                }
+            }
+        }
+        new_files.push(path);
+    }
+
+    new_files.sort_by(|a, b| path_order(a, b));
+    Ok(new_files)
+}
+
+/// Adds every .cbz under `dir` to `found`. Subdirectories that can't be read are reported and skipped
+pub fn walk(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            if let Err(e) = walk(&path, found) {
+                log::warn!("couldn't scan {:?}: {}", path, e);
+            }
+        } else if file_type.is_file() && path.extension().unwrap_or_default() == "cbz" {
+            found.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Orders paths the way a person would read chapter numbers: directory first, then the file name
+/// with runs of digits compared as numbers, so "Chapter 9" < "Chapter 10" < "Chapter 10.5"
+pub fn path_order(a: &Path, b: &Path) -> Ordering {
+    let natural = |a: Option<&std::ffi::OsStr>, b: Option<&std::ffi::OsStr>| {
+        natural_cmp(&a.unwrap_or_default().to_string_lossy(), &b.unwrap_or_default().to_string_lossy())
+    };
+    natural(a.parent().map(Path::as_os_str), b.parent().map(Path::as_os_str))
+        .then_with(|| natural(a.file_stem(), b.file_stem()))
+        .then_with(|| a.cmp(b))
+}
+
+fn natural_cmp(mut a: &str, mut b: &str) -> Ordering {
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, rest_a) = a.split_at(a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len()));
+                let (y, rest_b) = b.split_at(b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len()));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                // no leading zeros, so the longer number is the bigger one
+                match x.len().cmp(&y.len()).then_with(|| x.cmp(y)) {
+                    Ordering::Equal => (a, b) = (rest_a, rest_b),
+                    order => return order,
+                }
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(&y),
+            (Some(x), Some(_)) => (a, b) = (&a[x.len_utf8()..], &b[x.len_utf8()..]),
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn chapters_are_in_reading_order() {
+    let mut paths: Vec<PathBuf> = [
+        "Vol 2/Chapter 10.cbz",
+        "Vol 10/Chapter 1.cbz",
+        "Vol 2/Chapter 10.5.cbz",
+        "Vol 2/Chapter 9.cbz",
+        "Vol 2/Chapter 011.cbz",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .collect();
+    paths.sort_by(|a, b| path_order(a, b));
+
+    let expected = [
+        "Vol 2/Chapter 9.cbz",
+        "Vol 2/Chapter 10.cbz",
+        "Vol 2/Chapter 10.5.cbz",
+        "Vol 2/Chapter 011.cbz",
+        "Vol 10/Chapter 1.cbz",
+    ];
+    assert_eq!(paths, expected.iter().map(PathBuf::from).collect::<Vec<_>>());
+}
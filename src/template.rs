@@ -0,0 +1,70 @@
+/// Everything `[templates]` can use
+pub const PLACEHOLDERS: &[&str] = &["series", "title", "chapter", "part", "parts", "pages", "size", "subject"];
+
+/// What `templates.batch_subject` can use
+pub const BATCH_PLACEHOLDERS: &[&str] = &["count", "series", "size", "subject"];
+
+/// Fills in the `{name}` placeholders of a template. `{{` and `}}` are literal braces.
+/// Templates are checked when the config is loaded, one that doesn't parse anyway is used as is
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+    let lookup = |name: &str| values.iter().find(|(n, _)| *n == name).map(|(_, v)| v.clone());
+    expand(template, lookup).unwrap_or_else(|_| template.to_string())
+}
+
+/// Errors on braces that don't match and on placeholders not in `names`
+pub fn check(template: &str, names: &[&str]) -> Result<(), String> {
+    expand(template, |name| names.contains(&name).then(String::new))
+        .map(|_| ())
+        .map_err(|e| format!("{}, use one of {{{}}}", e, names.join("}, {")))
+}
+
+fn expand(template: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unclosed {{ in {:?}", template)),
+                    }
+                }
+                let value = lookup(name.trim())
+                    .ok_or_else(|| format!("unknown placeholder {{{}}} in {:?}", name, template))?;
+                out.push_str(&value);
+            }
+            '}' => return Err(format!("unmatched }} in {:?}, write }}}} for a brace", template)),
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn placeholders_are_filled_in() {
+    let values = [("series", "Frieren".to_string()), ("chapter", "12".to_string())];
+    assert_eq!(render("{series} #{chapter}", &values), "Frieren #12");
+    assert_eq!(render("{{{series}}}", &values), "{Frieren}");
+
+    assert!(check("{part}/{parts} {series}", PLACEHOLDERS).is_ok());
+    assert!(check("{volume}", PLACEHOLDERS).unwrap_err().contains("unknown placeholder {volume}"));
+    assert!(check("{series", PLACEHOLDERS).is_err());
+    assert!(check("series}", PLACEHOLDERS).is_err());
+}
@@ -0,0 +1,278 @@
+use notify::{
+    event::{AccessKind, AccessMode, EventKind, MetadataKind, ModifyKind, RenameMode},
+    Event, PollWatcher, RecommendedWatcher, Watcher,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{WatcherConfig, WatcherMode},
+    scan,
+};
+
+/// How we learn about new files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchMode {
+    /// inotify (or whatever the platform offers). Cheap, but blind on network filesystems
+    Native,
+    /// stat the whole tree every interval. Works everywhere
+    Poll(Duration),
+}
+
+impl WatchMode {
+    pub fn from_config(config: &WatcherConfig) -> WatchMode {
+        match config.mode {
+            WatcherMode::Native => WatchMode::Native,
+            WatcherMode::Poll => WatchMode::Poll(Duration::from_secs(config.poll_interval.max(1))),
+        }
+    }
+
+    /// How long a new file has to stay untouched before we consider it fully written
+    pub fn quiet_period(self, config: &WatcherConfig) -> Duration {
+        let quiet = Duration::from_millis(config.debounce_ms);
+        match self {
+            WatchMode::Native => quiet,
+            // the poll watcher only notices a change once per interval
+            WatchMode::Poll(interval) => quiet.max(interval * 2),
+        }
+    }
+}
+
+pub fn new_watcher(mode: WatchMode, tx: Sender<notify::Result<Event>>) -> notify::Result<Box<dyn Watcher>> {
+    match mode {
+        WatchMode::Native => Ok(Box::new(RecommendedWatcher::new(tx, notify::Config::default())?)),
+        WatchMode::Poll(interval) => {
+            let config = notify::Config::default().with_poll_interval(interval);
+            Ok(Box::new(PollWatcher::new(tx, config)?))
+        }
+    }
+}
+
+struct Pending {
+    last_event: Instant,
+    size: Option<u64>,
+}
+
+/// Decides when a new or rewritten .cbz has finished writing.
+///
+/// A file is done when the writer closes it (inotify close-write) or renames it into place, or,
+/// where neither is reported, when it has seen no events for `quiet` and its size hasn't moved
+/// since the last one. Whether a rewritten file actually needs sending again is up to the job store.
+pub struct Debouncer {
+    quiet: Duration,
+    pending: HashMap<PathBuf, Pending>,
+    ready: Vec<PathBuf>,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration) -> Debouncer {
+        Debouncer {
+            quiet,
+            pending: HashMap::new(),
+            ready: Vec::new(),
+        }
+    }
+
+    pub fn event(&mut self, event: Event) {
+        let mut paths = event.paths;
+
+        match event.kind {
+            // renamed within the tree, paths are [from, to]. Only the destination is interesting
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+                let to = paths.pop().unwrap_or_default();
+                self.forget(&paths[0]);
+                for path in cbz_files(to) {
+                    self.arrived(path);
+                }
+            }
+            // renamed into place or moved in from outside the tree: the writer is already done.
+            // A directory brings its .cbz files along
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in paths.into_iter().flat_map(cbz_files) {
+                    self.arrived(path);
+                }
+            }
+            // moved away
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in paths {
+                    self.forget(&path);
+                }
+            }
+            // a rename the platform couldn't pair up, all we can do is look
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in paths.into_iter().filter(|p| is_cbz(p)) {
+                    if path.is_file() {
+                        self.touch(path);
+                    } else {
+                        self.pending.remove(&path);
+                    }
+                }
+            }
+            // a new directory may have been filled before we started watching it, e.g. `cp -r`.
+            // What is in there may still be written, so let it settle like any other new file
+            EventKind::Create(_) => {
+                for path in paths.into_iter().flat_map(cbz_files) {
+                    self.touch(path);
+                }
+            }
+            // files being (over)written
+            EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+            | EventKind::Modify(ModifyKind::Any) => {
+                for path in paths.into_iter().filter(|p| is_cbz(p)) {
+                    self.touch(path);
+                }
+            }
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for path in paths.into_iter().filter(|p| is_cbz(p)) {
+                    self.arrived(path);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn arrived(&mut self, path: PathBuf) {
+        self.pending.remove(&path);
+        self.ready.push(path);
+    }
+
+    /// Drops a file, or everything under a directory, that was still being written
+    fn forget(&mut self, path: &Path) {
+        self.pending.retain(|p, _| !p.starts_with(path));
+    }
+
+    fn touch(&mut self, path: PathBuf) {
+        let size = std::fs::metadata(&path).map(|m| m.len()).ok();
+        self.pending.insert(path, Pending { last_event: Instant::now(), size });
+    }
+
+    /// Files that have finished writing since the last call, in `scan::path_order`
+    pub fn ready(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        let mut settled = Vec::new();
+
+        self.pending.retain(|path, pending| {
+            if now.duration_since(pending.last_event) < self.quiet {
+                return true;
+            }
+            match std::fs::metadata(path) {
+                Ok(m) if Some(m.len()) == pending.size => {
+                    settled.push(path.clone());
+                    false
+                }
+                // still growing without telling us, give it another round
+                Ok(m) => {
+                    pending.size = Some(m.len());
+                    pending.last_event = now;
+                    true
+                }
+                // gone before it was finished
+                Err(_) => false,
+            }
+        });
+
+        let mut ready = std::mem::take(&mut self.ready);
+        ready.extend(settled);
+        ready.sort_by(|a, b| scan::path_order(a, b));
+        ready.dedup();
+        ready
+    }
+
+    /// How long the event loop may block before `ready` has something new to say
+    pub fn timeout(&self) -> Duration {
+        let now = Instant::now();
+        self.pending
+            .values()
+            .map(|p| (p.last_event + self.quiet).saturating_duration_since(now))
+            .min()
+            .unwrap_or(Duration::from_secs(60))
+            .max(Duration::from_millis(50))
+    }
+}
+
+fn is_cbz(path: &Path) -> bool {
+    path.extension().unwrap_or_default() == "cbz"
+}
+
+/// The path itself if it is a .cbz, the .cbz files under it if it is a directory
+fn cbz_files(path: PathBuf) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return if is_cbz(&path) { vec![path] } else { Vec::new() };
+    }
+    let mut found = Vec::new();
+    if let Err(e) = scan::walk(&path, &mut found) {
+        log::warn!("couldn't scan {:?}: {}", path, e);
+    }
+    found
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+fn notify_event(kind: EventKind, paths: &[&Path]) -> Event {
+    paths.iter().fold(Event::new(kind), |event, p| event.add_path(p.to_path_buf()))
+}
+
+#[test]
+fn finished_writes_are_ready_at_once() {
+    let dir = crate::workdir::TempDir::new("debounce");
+    std::fs::create_dir_all(dir.join("moved")).unwrap();
+    let file = |name: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, b"cbz").unwrap();
+        path
+    };
+    let (a, b, c, d) = (file("a.cbz"), file("b.cbz"), file("c.cbz"), file("moved/d.cbz"));
+    let mut debouncer = Debouncer::new(Duration::from_secs(60));
+    let write = EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Any));
+
+    // closed after writing
+    debouncer.event(notify_event(write, &[&a]));
+    debouncer.event(notify_event(EventKind::Access(AccessKind::Close(AccessMode::Write)), &[&a]));
+    assert_eq!(debouncer.ready(), std::slice::from_ref(&a));
+
+    // written under a temporary name, then renamed into place
+    let part = dir.join("b.cbz.part");
+    debouncer.event(notify_event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &[&part, &b]));
+    // moved in from outside the tree, a whole directory at once
+    debouncer.event(notify_event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &[&c]));
+    debouncer.event(notify_event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &[&dir.join("moved")]));
+    assert_eq!(debouncer.ready(), [b, c, d.clone()]);
+
+    // moved away or deleted halfway through
+    debouncer.event(notify_event(write, &[&a]));
+    debouncer.event(notify_event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &[&a]));
+    debouncer.event(notify_event(write, &[&d]));
+    debouncer.event(notify_event(EventKind::Remove(notify::event::RemoveKind::Folder), &[&dir.join("moved")]));
+    assert!(debouncer.pending.is_empty());
+    assert!(debouncer.ready().is_empty());
+}
+
+#[test]
+fn quiet_files_settle_once_their_size_stops() {
+    let dir = crate::workdir::TempDir::new("settle");
+    let path = dir.join("a.cbz");
+    std::fs::write(&path, b"c").unwrap();
+    let quiet = Duration::from_millis(50);
+    let mut debouncer = Debouncer::new(quiet);
+
+    debouncer.event(notify_event(EventKind::Create(notify::event::CreateKind::File), &[&path]));
+    assert!(debouncer.ready().is_empty());
+
+    // grew without an event, so it isn't done yet
+    std::fs::write(&path, b"cbz").unwrap();
+    std::thread::sleep(quiet);
+    assert!(debouncer.ready().is_empty());
+
+    // quiet and the same size as last time
+    std::thread::sleep(quiet);
+    assert_eq!(debouncer.ready(), [path]);
+    assert!(debouncer.ready().is_empty());
+}
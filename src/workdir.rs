@@ -0,0 +1,130 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::{DirectoriesConfig, OptionsConfig};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the epubs of one job are built. Every job gets its own directory under the work dir,
+/// so jobs running side by side never see each other's files.
+///
+/// The directory is only removed by `remove`, which is called once everything was sent or moved
+/// to the dead-letter directory. If a chapter can't be converted it stays behind for inspection.
+pub struct JobDir {
+    path: PathBuf,
+}
+
+impl JobDir {
+    pub fn create(root: &Path, source: &Path) -> io::Result<JobDir> {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let stem: String = source
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("job")
+            .chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .take(64)
+            .collect();
+
+        let path = root.join(format!(
+            "{}-{}-{}-{}",
+            stamp,
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            stem
+        ));
+        std::fs::create_dir_all(&path)?;
+
+        Ok(JobDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn remove(self) -> io::Result<()> {
+        std::fs::remove_dir_all(&self.path)
+    }
+}
+
+/// Where a dry run leaves its epubs (`options.dry_run_dir`), `<work dir>/dry-run` if not set
+pub fn dry_run_root(options: &OptionsConfig, directories: &DirectoriesConfig) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match &options.dry_run_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(work_root(directories)?.join("dry-run")),
+    }
+}
+
+/// The configured work dir (`directories.work`), `~/.cache/kiyomi/work` if not set
+pub fn work_root(config: &DirectoriesConfig) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match &config.work {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(dirs::cache_dir()
+            .ok_or("the cache directory could not be found")?
+            .join("kiyomi/work")),
+    }
+}
+
+/// Where epubs that couldn't be sent are kept (`directories.dead_letter`),
+/// `~/.cache/kiyomi/dead-letter` if not set
+pub fn dead_letter_root(config: &DirectoriesConfig) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match &config.dead_letter {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(dirs::cache_dir()
+            .ok_or("the cache directory could not be found")?
+            .join("kiyomi/dead-letter")),
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+/// A fresh directory for a test, removed again when it's dropped, even if the test fails
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "kiyomi-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
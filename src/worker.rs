@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+};
+
+type Handler = dyn Fn(&Path) + Send + Sync;
+
+#[derive(Default)]
+struct Queue {
+    /// waiting jobs per series, in submission order
+    jobs: HashMap<PathBuf, VecDeque<PathBuf>>,
+    /// series that have waiting jobs and nobody working on them, oldest first
+    ready: VecDeque<PathBuf>,
+    /// series a worker is currently busy with
+    busy: HashSet<PathBuf>,
+    shutdown: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    handler: Box<Handler>,
+}
+
+/// A fixed number of worker threads running jobs off the event loop.
+///
+/// Jobs of the same series (the .cbz's directory) never run side by side and always run in the
+/// order they were submitted, so chapters reach the kindle in order. Different series run in
+/// parallel.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new<F>(size: usize, handler: F) -> WorkerPool
+    where
+        F: Fn(&Path) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            handler: Box::new(handler),
+        });
+
+        let threads = (0..size.max(1))
+            .map(|i| {
+                let shared = shared.clone();
+                std::thread::Builder::new()
+                    .name(format!("kiyomi-worker-{}", i))
+                    .spawn(move || work(&shared))
+                    .expect("couldn't spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool { shared, threads }
+    }
+
+    pub fn submit(&self, file: PathBuf) {
+        let series = series_of(&file);
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        let waiting = queue.jobs.entry(series.clone()).or_default();
+        waiting.push_back(file);
+        if waiting.len() == 1 && !queue.busy.contains(&series) {
+            queue.ready.push_back(series);
+        }
+
+        self.shared.changed.notify_all();
+    }
+
+    /// Runs everything that was submitted, then stops the workers
+    pub fn finish(self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let (series, file) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(series) = queue.ready.pop_front() {
+                    let file = queue
+                        .jobs
+                        .get_mut(&series)
+                        .and_then(|j| j.pop_front())
+                        .expect("ready series without jobs");
+                    queue.busy.insert(series.clone());
+                    break (series, file);
+                }
+                if queue.shutdown && queue.busy.is_empty() {
+                    return;
+                }
+                queue = shared.changed.wait(queue).unwrap();
+            }
+        };
+
+        // a job that panics mustn't take its worker down, or leave its series busy forever
+        if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| (shared.handler)(&file))) {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            log::error!("job for {:?} panicked: {}", file, message);
+        }
+
+        let mut queue = shared.queue.lock().unwrap();
+        queue.busy.remove(&series);
+        if queue.jobs.get(&series).is_some_and(|j| !j.is_empty()) {
+            queue.ready.push_back(series);
+        } else {
+            queue.jobs.remove(&series);
+        }
+        shared.changed.notify_all();
+    }
+}
+
+fn series_of(file: &Path) -> PathBuf {
+    file.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+//                                   TESTS                                     //
+/////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn pool_keeps_series_order() {
+    let done = Arc::new(Mutex::new(Vec::new()));
+
+    let log = done.clone();
+    let pool = WorkerPool::new(4, move |file| {
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        log.lock().unwrap().push(file.to_path_buf());
+    });
+    for chapter in 1..=5 {
+        pool.submit(PathBuf::from(format!("/manga/a/{}.cbz", chapter)));
+        pool.submit(PathBuf::from(format!("/manga/b/{}.cbz", chapter)));
+    }
+    pool.finish();
+
+    let done = done.lock().unwrap();
+    assert_eq!(done.len(), 10);
+    for series in ["/manga/a", "/manga/b"] {
+        let order: Vec<_> = done.iter().filter(|f| f.starts_with(series)).collect();
+        let expected: Vec<_> = (1..=5).map(|c| PathBuf::from(format!("{}/{}.cbz", series, c))).collect();
+        assert_eq!(order, expected.iter().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn a_panicking_job_doesnt_stall_its_series() {
+    let done = Arc::new(Mutex::new(Vec::new()));
+
+    let log = done.clone();
+    let pool = WorkerPool::new(1, move |file| {
+        if file.ends_with("1.cbz") {
+            panic!("broken chapter");
+        }
+        log.lock().unwrap().push(file.to_path_buf());
+    });
+    for chapter in 1..=3 {
+        pool.submit(PathBuf::from(format!("/manga/a/{}.cbz", chapter)));
+    }
+    pool.finish();
+
+    assert_eq!(*done.lock().unwrap(), [PathBuf::from("/manga/a/2.cbz"), PathBuf::from("/manga/a/3.cbz")]);
+}